@@ -1,30 +1,327 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Range};
+use std::path::Path;
 
 #[cfg(test)]
 mod tests;
 
-use faer::{Col, Mat, fx128, mat};
+#[cfg(test)]
+thread_local! {
+    /// Number of `turn_big` expansions built by the current thread.
+    static EXPANSIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+use faer::col::ColMut;
+use faer::{Col, Mat, mat};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::kernel;
+pub use crate::kernel::{C, Scalar, cplx, inv_sqrt2};
+use crate::noise::NoiseModel;
+use crate::npy;
+use crate::observables::Hamiltonian;
+use crate::qasm;
+use crate::stabilizer::StabilizerSim;
 
-pub type C = fx128;
+pub const ONE: C = cplx(1.0, 0.0);
+pub const Z: C = cplx(0.0, 0.0);
+pub const IM: C = cplx(0.0, 1.0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitError {
+    /// A qubit index is not smaller than the circuit's qubit count.
+    QubitOutOfRange(usize),
+    /// A gate could not be built (wrong size, not unitary or repeated qubit).
+    InvalidGate,
+    /// A gate outside of the Clifford group was given to the stabilizer simulator.
+    NonClifford,
+    /// A measurement outcome is not 0 or 1, or has zero probability.
+    InvalidOutcome(usize),
+    /// No register with this name was declared.
+    UnknownRegister(String),
+    /// Reading or writing a `.npy` file failed.
+    Npy(String),
+    /// A bitstring has the wrong length or contains characters other than 0 and 1.
+    InvalidBits(String),
+    /// A gate count is larger than the number of gates in the circuit.
+    GateCountOutOfRange(usize),
+    /// A Pauli string has the wrong length or letters other than I, X, Y and Z.
+    InvalidPauli(String),
+    /// Two circuits combined together act on different numbers of qubits.
+    QubitCountMismatch(usize, usize),
+    /// The final state has (near) zero norm, so some gate was not unitary.
+    StateCollapsed,
+    /// A matrix isn't unitary.
+    NonUnitary,
+    /// A matrix of this size doesn't match the number of qubits it acts on.
+    InvalidDimension(usize, usize),
+    /// Too few shots were requested to estimate a quantity.
+    InvalidShots(usize),
+    /// An OpenQASM program could not be imported.
+    Qasm(String),
+    /// A gate angle refers to a parameter with no bound value.
+    UnboundParameter(String),
+    /// An ancilla was freed while not in `|0⟩`.
+    DirtyAncilla(usize),
+    /// A qubit pinned to a classical value is the target of a gate.
+    FixedQubitTargeted(usize),
+    /// No gate has this name and numbers of parameters and qubits.
+    UnknownGate(String, usize, usize),
+    /// A gate can't be rewritten into the requested basis.
+    NoDecomposition(String),
+    /// The gate at this index, with this name, acts on qubits that aren't
+    /// coupled.
+    Uncoupled(usize, String),
+    /// A list meant to reorder the qubits isn't a permutation of them.
+    InvalidPermutation(Vec<usize>),
+    /// A matrix for a circuit on this many qubits would have more than
+    /// `2^MAX_QUBITS` entries.
+    TooManyQubits(usize),
+    /// A gate is conditioned on a classical bit no earlier gate measured into.
+    UndefinedClassicalBit(usize),
+    /// The gate at this index measures or is classically controlled, which
+    /// only `Circuit::run_shot` simulates.
+    ClassicalGate(usize),
+}
+
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitError::QubitOutOfRange(q) => write!(f, "qubit {} is out of range", q),
+            CircuitError::InvalidGate => write!(f, "invalid gate"),
+            CircuitError::NonClifford => write!(f, "circuit contains a non-Clifford gate"),
+            CircuitError::InvalidOutcome(o) => write!(f, "outcome {} cannot be observed", o),
+            CircuitError::UnknownRegister(name) => write!(f, "unknown register {}", name),
+            CircuitError::Npy(msg) => write!(f, "npy error: {}", msg),
+            CircuitError::InvalidBits(bits) => write!(f, "invalid bitstring {}", bits),
+            CircuitError::GateCountOutOfRange(n) => {
+                write!(f, "circuit has fewer than {} gates", n)
+            }
+            CircuitError::InvalidPauli(pauli) => write!(f, "invalid Pauli string {}", pauli),
+            CircuitError::QubitCountMismatch(a, b) => {
+                write!(f, "circuits act on {} and {} qubits", a, b)
+            }
+            CircuitError::StateCollapsed => write!(f, "the state collapsed to zero norm"),
+            CircuitError::NonUnitary => write!(f, "matrix is not unitary"),
+            CircuitError::InvalidDimension(rows, cols) => {
+                write!(f, "a {}x{} matrix cannot act on these qubits", rows, cols)
+            }
+            CircuitError::InvalidShots(n) => write!(f, "{} shots are not enough", n),
+            CircuitError::Qasm(msg) => write!(f, "qasm error: {}", msg),
+            CircuitError::UnboundParameter(name) => {
+                write!(f, "parameter {} is not bound", name)
+            }
+            CircuitError::DirtyAncilla(q) => write!(f, "ancilla {} is not back in |0⟩", q),
+            CircuitError::FixedQubitTargeted(q) => {
+                write!(f, "fixed qubit {} is the target of a gate", q)
+            }
+            CircuitError::UnknownGate(name, params, qubits) => write!(
+                f,
+                "no gate {} takes {} parameters and {} qubits",
+                name, params, qubits
+            ),
+            CircuitError::NoDecomposition(name) => {
+                write!(f, "gate {} has no decomposition into the basis", name)
+            }
+            CircuitError::Uncoupled(index, name) => {
+                write!(f, "gate {} ({}) acts on uncoupled qubits", index, name)
+            }
+            CircuitError::InvalidPermutation(order) => {
+                write!(f, "{:?} is not a permutation of the qubits", order)
+            }
+            CircuitError::TooManyQubits(n) => {
+                write!(f, "{} qubits are too many to build the matrix", n)
+            }
+            CircuitError::UndefinedClassicalBit(c) => {
+                write!(f, "classical bit {} is read before any measurement", c)
+            }
+            CircuitError::ClassicalGate(i) => {
+                write!(f, "gate {} is classical, run the circuit with run_shot", i)
+            }
+        }
+    }
+}
 
-pub const ONE: C = C::from_f64(1.0);
-pub const Z: C = C::ZERO;
+impl std::error::Error for CircuitError {}
 
-pub struct Gate {
-    mat: Mat<C>,
+/// A unitary acting on a list of qubits.
+///
+/// Gates own their matrix and never use interior mutability, so they are
+/// `Send + Sync` whenever `S` is.
+#[derive(Clone)]
+pub struct Gate<S = C> {
+    name: String,
+    mat: Mat<S>,
     targets: Vec<usize>,
+    /// How many of the trailing `targets` are controls added by `controlled`.
+    controls: usize,
+    /// Set for rotations whose angle is only known when the circuit is run.
+    param: Option<Unbound>,
+    /// Shown by `draw` and `to_qasm` instead of, or next to, the name.
+    label: Option<String>,
+    /// Set for measurements and classically controlled gates.
+    classical: Option<Classical>,
+}
+
+/// Classical side of a gate, see `Circuit::measure` and `Circuit::x_if`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Classical {
+    /// Measures the target into this classical bit. The matrix is unused.
+    Measure(usize),
+    /// Applies the gate only when this classical bit is 1.
+    If(usize),
+}
+
+/// Angle of a rotation gate, either fixed or a named parameter bound by
+/// `Circuit::run_with`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parameter {
+    Value(f64),
+    Param(String),
+}
+
+impl From<f64> for Parameter {
+    fn from(value: f64) -> Self {
+        Parameter::Value(value)
+    }
 }
 
-pub struct Circuit {
+/// Named angle of a rotation gate. The gate is rebuilt as
+/// `build(scale * θ, target)` once `θ` is known.
+#[derive(Clone)]
+struct Unbound {
+    name: String,
+    scale: f64,
+    build: fn(f64, usize) -> Gate,
+}
+
+/// A list of gates applied in order to `qubits` qubits starting in
+/// `|0...0⟩`.
+///
+/// Simulation only borrows the circuit immutably, so a single circuit can
+/// be shared between threads and run from each of them.
+#[derive(Clone)]
+pub struct Circuit<S = C> {
     qubits: usize,
-    gates: Vec<Gate>,
+    gates: Vec<Gate<S>>,
+    registers: Vec<(String, usize)>,
+    strategy: Strategy,
+    /// Ancillas released by `free_ancilla`, reused by `alloc_ancilla`.
+    free_ancillas: Vec<usize>,
+    /// Tolerance of the normalization and measurement checks, see
+    /// `set_tolerance`.
+    tolerance: f64,
+}
+
+/// How `run` and `statevector` apply each gate to the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Multiplies the state by the `2^n x 2^n` matrix from `turn_big`.
+    #[default]
+    Dense,
+    /// Updates the state in place with `Gate::apply`.
+    Sparse,
+    /// Picks one of the above for each gate from its width.
+    Auto,
 }
 
-pub fn norm(x: fx128) -> f64 {
-    (x.0 * x.0 + x.1 * x.1).sqrt()
+impl Strategy {
+    /// Returns the strategy used for a gate touching `width` of the `n`
+    /// qubits of a circuit, which is never `Auto`.
+    ///
+    /// `Auto` only expands gates acting on the whole register, where
+    /// `turn_big` doesn't add any zero entries.
+    pub fn resolve(self, width: usize, n: usize) -> Strategy {
+        match self {
+            Strategy::Auto if width < n => Strategy::Sparse,
+            Strategy::Auto => Strategy::Dense,
+            s => s,
+        }
+    }
+}
+
+/// Diagonal unitary `|x⟩ ↦ e^{i f(x)} |x⟩` whose phase is written as a sum
+/// of parities, `f(x) = global + Σ angle · (x · mask mod 2)`.
+///
+/// Built by `Circuit::extract_phase_poly` and `Circuit::phase_poly_runs` from
+/// a run of diagonal gates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhasePoly {
+    pub qubits: usize,
+    pub global: f64,
+    /// `(mask, angle)` pairs with a non-zero angle, by increasing mask.
+    pub terms: Vec<(usize, f64)>,
+}
+
+impl PhasePoly {
+    /// Builds a circuit applying the phase polynomial: each term computes
+    /// the parity of its qubits into the highest one with CX gates, applies
+    /// a `p` gate there and uncomputes the parity.
+    pub fn to_circuit(&self) -> Circuit {
+        let mut circuit = Circuit::new(self.qubits);
+        if self.global.abs() > 1E-10 {
+            circuit.global_phase(self.global).unwrap();
+        }
+        for &(mask, angle) in &self.terms {
+            let qubits: Vec<usize> = (0..self.qubits).filter(|q| mask >> q & 1 == 1).collect();
+            let (&last, rest) = qubits.split_last().unwrap();
+            for &q in rest {
+                circuit.cx(q, last).unwrap();
+            }
+            circuit.p(angle, last).unwrap();
+            for &q in rest.iter().rev() {
+                circuit.cx(q, last).unwrap();
+            }
+        }
+        circuit
+    }
+}
+
+/// Base-2 logarithm of the largest number of entries of the dense matrices
+/// built by `unitary` and `choi_matrix`, i.e. as many amplitudes as a
+/// statevector on `MAX_QUBITS` qubits. Their `2^n x 2^n` matrices only fit
+/// for `n <= MAX_QUBITS / 2`.
+pub const MAX_QUBITS: usize = 28;
+
+/// Tolerance used by the approximate comparisons of this module, such as
+/// `mat_approx_eq` and `is_identity`.
+pub const TOLERANCE: f64 = 1E-5;
+
+pub fn norm<S: Scalar>(x: S) -> f64 {
+    x.modulus()
+}
+
+/// Checks whether `a` and `b` are closer than `tol`, e.g. `TOLERANCE`, to
+/// ignore numerical noise when comparing amplitudes or matrix entries.
+pub fn approx_eq<S: Scalar>(a: S, b: S, tol: f64) -> bool {
+    norm(a - b) < tol
+}
+
+pub fn mat_approx_eq<S: Scalar>(a: &Mat<S>, b: &Mat<S>) -> bool {
+    if a.nrows() != b.nrows() || a.ncols() != b.ncols() {
+        return false;
+    }
+    for j in 0..a.ncols() {
+        for i in 0..a.nrows() {
+            if norm(a[(i, j)] - b[(i, j)]) > TOLERANCE {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+pub fn is_identity<S: Scalar>(m: &Mat<S>) -> bool {
+    is_identity_within(m, TOLERANCE)
 }
 
-pub fn is_identity(m: &Mat<C>) -> bool {
+/// Same as `is_identity`, with entries allowed to be `tol` away from the
+/// identity.
+pub fn is_identity_within<S: Scalar>(m: &Mat<S>, tol: f64) -> bool {
     if m.ncols() != m.nrows() {
         return false;
     }
@@ -33,11 +330,11 @@ pub fn is_identity(m: &Mat<C>) -> bool {
         for i in 0..n {
             let val = m[(i, j)];
             if i == j {
-                if norm(val - ONE) > 1E-5 {
+                if norm(val - S::from_parts(1.0, 0.0)) > tol {
                     return false;
                 }
             } else {
-                if norm(val) > 1E-5 {
+                if norm(val) > tol {
                     return false;
                 }
             }
@@ -46,66 +343,344 @@ pub fn is_identity(m: &Mat<C>) -> bool {
     true
 }
 
-pub fn is_unit(mat: &Mat<C>) -> bool {
+/// Checks that `mat` is unitary, i.e. that both `M M†` and `M† M` are the
+/// identity.
+///
+/// Both products are checked since, within the tolerance of `is_identity`,
+/// a matrix can be close enough on one side but not on the other.
+pub fn is_unit<S: Scalar>(mat: &Mat<S>) -> bool {
+    is_unit_within(mat, TOLERANCE)
+}
+
+/// Same as `is_unit`, with both products allowed to be `tol` away from the
+/// identity.
+pub fn is_unit_within<S: Scalar>(mat: &Mat<S>, tol: f64) -> bool {
     let adjoint = mat.clone();
     let adjoint = adjoint.adjoint();
     let det = mat.determinant();
     if norm(det) < 1E-10 {
         return false;
     }
-    if !is_identity(&(mat.clone() * adjoint)) {
+    if !is_identity_within(&(mat.clone() * adjoint), tol) {
+        return false;
+    }
+    if !is_identity_within(&(adjoint * mat), tol) {
         return false;
     }
     true
 }
 
-impl Gate {
-    pub fn new(mat: Mat<C>, targets: Vec<usize>) -> Option<Self> {
-        if mat.ncols() != mat.nrows() {
-            return None;
+/// Kronecker product `a ⊗ b`, where the index of `b` is the low part of the
+/// resulting index.
+pub fn kron<S: Scalar>(a: &Mat<S>, b: &Mat<S>) -> Mat<S> {
+    let (br, bc) = (b.nrows(), b.ncols());
+    Mat::from_fn(a.nrows() * br, a.ncols() * bc, |i, j| {
+        a[(i / br, j / bc)] * b[(i % br, j % bc)]
+    })
+}
+
+/// Panics with the first differing index and both amplitudes unless every
+/// entry of `actual` is within `tol` of the matching entry of `expected`.
+#[cfg(any(test, feature = "test-util"))]
+pub fn assert_state_approx<S: Scalar>(actual: &Col<S>, expected: &Col<S>, tol: f64) {
+    assert_eq!(
+        actual.nrows(),
+        expected.nrows(),
+        "statevectors have different lengths"
+    );
+    for (i, (a, b)) in actual.iter().zip(expected.iter()).enumerate() {
+        if norm(*a - *b) > tol {
+            panic!(
+                "states differ at index {}: {:.6}{:+.6}i != {:.6}{:+.6}i",
+                i,
+                a.real(),
+                a.imag(),
+                b.real(),
+                b.imag()
+            );
         }
-        let power = (2 as u32).pow(targets.len() as u32) as usize;
-        if mat.ncols() != power {
-            return None;
+    }
+}
+
+/// Returns `⟨state|P|state⟩` for a Pauli string `P` whose rightmost letter
+/// acts on qubit 0.
+fn pauli_expectation<S: Scalar>(state: &Col<S>, pauli: &str) -> f64 {
+    let mut res = 0.0;
+    for i in 0..state.nrows() {
+        // P|i⟩ = (re + i im)|j⟩
+        let (mut j, mut re, mut im) = (i, 1.0, 0.0);
+        for (q, letter) in pauli.chars().rev().enumerate() {
+            let bit = (i >> q) & 1 == 1;
+            let sign = if bit { -1.0 } else { 1.0 };
+            match letter {
+                'X' => j ^= 1 << q,
+                'Y' => {
+                    j ^= 1 << q;
+                    (re, im) = (-im * sign, re * sign);
+                }
+                'Z' => (re, im) = (re * sign, im * sign),
+                _ => {}
+            }
+        }
+        let (a, b) = (state[j], state[i]);
+        // Real part of conj(a) * (re + i im) * b
+        let (pr, pi) = (re * b.real() - im * b.imag(), re * b.imag() + im * b.real());
+        res += a.real() * pr + a.imag() * pi;
+    }
+    res
+}
+
+/// Rewrites a gate in terms of other gates, for `Circuit::transpile`.
+/// `qubits` are the gate's targets followed by its controls.
+fn decomposition(name: &str, qubits: &[usize]) -> Option<Vec<Gate>> {
+    let gates = match (name, qubits) {
+        ("cx", [t, c]) => vec![Gate::h(*t), Gate::cz(*c, *t)?, Gate::h(*t)],
+        ("cz", [t, c]) => vec![Gate::h(*t), Gate::cx(*c, *t)?, Gate::h(*t)],
+        ("swap", [a, b]) => vec![Gate::cx(*a, *b)?, Gate::cx(*b, *a)?, Gate::cx(*a, *b)?],
+        ("z", [t]) => vec![Gate::s(*t), Gate::s(*t)],
+        ("s", [t]) => vec![Gate::t(*t), Gate::t(*t)],
+        ("sdg", [t]) => vec![Gate::s(*t), Gate::z(*t)],
+        // T^8 = I, so T† = T^7
+        ("tdg", [t]) => vec![Gate::t(*t); 7],
+        ("ccx", [t, a, b]) => {
+            let (t, a, b) = (*t, *a, *b);
+            vec![
+                Gate::h(t),
+                Gate::cx(b, t)?,
+                Gate::t(t).dagger(),
+                Gate::cx(a, t)?,
+                Gate::t(t),
+                Gate::cx(b, t)?,
+                Gate::t(t).dagger(),
+                Gate::cx(a, t)?,
+                Gate::t(b),
+                Gate::t(t),
+                Gate::h(t),
+                Gate::cx(a, b)?,
+                Gate::t(a),
+                Gate::t(b).dagger(),
+                Gate::cx(a, b)?,
+            ]
+        }
+        _ => return None,
+    };
+    Some(gates)
+}
+
+/// Returns the argument of `re + i im` in `(-π, π]`.
+pub(crate) fn phase_angle(re: f64, im: f64) -> f64 {
+    let phase = im.atan2(re);
+    if phase <= -std::f64::consts::PI {
+        phase + 2.0 * std::f64::consts::PI
+    } else {
+        phase
+    }
+}
+
+/// Returns the sum of the diagonal entries of a square matrix.
+///
+/// # Panics
+///
+/// Panics if `m` isn't square.
+pub fn trace<S: Scalar>(m: &Mat<S>) -> S {
+    assert_eq!(m.nrows(), m.ncols(), "trace of a non-square matrix");
+    (0..m.nrows()).fold(S::from_parts(0.0, 0.0), |acc, i| acc + m[(i, i)])
+}
+
+/// Returns the overlap `⟨a|b⟩` of two statevectors.
+///
+/// # Panics
+///
+/// Panics if the vectors have different lengths.
+pub fn inner_product<S: Scalar>(a: &Col<S>, b: &Col<S>) -> S {
+    assert_eq!(a.nrows(), b.nrows(), "statevectors have different lengths");
+    a.iter()
+        .zip(b.iter())
+        .fold(S::from_parts(0.0, 0.0), |acc, (x, y)| {
+            acc + S::from_parts(x.real(), -x.imag()) * *y
+        })
+}
+
+/// Checks whether `a` equals `b` up to a global phase once the qubits are
+/// relabelled: qubit `q` of `a` is qubit `perm[q]` of `b`, like the layout
+/// returned by `Circuit::route_with_layout`.
+///
+/// Returns `false` if the lengths differ or `perm` isn't a permutation of
+/// the qubits.
+pub fn states_equal_up_to_permutation<S: Scalar>(a: &Col<S>, b: &Col<S>, perm: &[usize]) -> bool {
+    let n = perm.len();
+    let mut sorted = perm.to_vec();
+    sorted.sort_unstable();
+    if a.nrows() != b.nrows() || a.nrows() != 1 << n || sorted != (0..n).collect::<Vec<_>>() {
+        return false;
+    }
+    let permuted = Col::from_fn(b.nrows(), |x| {
+        b[(0..n).fold(0, |acc, q| acc | (((x >> q) & 1) << perm[q]))]
+    });
+    let norm_sq = |v: &Col<S>| v.iter().map(|x| norm(*x) * norm(*x)).sum::<f64>();
+    let (na, nb) = (norm_sq(a).sqrt(), norm_sq(b).sqrt());
+    // Cauchy-Schwarz is an equality exactly when the states are parallel
+    (na - nb).abs() < TOLERANCE && norm(inner_product(a, &permuted)) > na * nb - TOLERANCE
+}
+
+/// Reverses the order of the `n` lowest bits of `index`, so bit `q` moves to
+/// bit `n - 1 - q`. Higher bits are dropped.
+pub fn bit_reverse(index: usize, n: usize) -> usize {
+    (0..n).fold(0, |acc, q| acc | ((index >> q) & 1) << (n - 1 - q))
+}
+
+/// Reorders the amplitudes of an `n`-qubit statevector by bit-reversed
+/// index, i.e. relabels qubit `q` as qubit `n - 1 - q`.
+///
+/// Panics if `v` doesn't have `2^n` entries.
+pub fn reverse_statevector<S: Scalar>(v: &Col<S>, n: usize) -> Col<S> {
+    assert_eq!(
+        v.nrows(),
+        1 << n,
+        "statevector size doesn't match {} qubits",
+        n
+    );
+    Col::from_fn(v.nrows(), |i| v[bit_reverse(i, n)])
+}
+
+/// Checks whether two gates commute when acting on an `n`-qubit register.
+///
+/// Gates acting on disjoint qubits always commute, otherwise the expanded
+/// matrices are multiplied in both orders and compared.
+pub fn gates_commute<S: Scalar>(a: &Gate<S>, b: &Gate<S>, n: usize) -> bool {
+    if a.targets.iter().all(|q| !b.targets.contains(q)) {
+        return true;
+    }
+    let a = a.turn_big(n);
+    let b = b.turn_big(n);
+    mat_approx_eq(&(&a * &b), &(&b * &a))
+}
+
+/// Same as `gates_commute`, with both gates relabelled onto the union of
+/// their qubits so the check never expands them to the whole register.
+fn commute_locally<S: Scalar>(a: &Gate<S>, b: &Gate<S>) -> bool {
+    let mut union = a.targets.clone();
+    union.extend(b.targets.iter().filter(|q| !a.targets.contains(q)));
+    let local = |g: &Gate<S>| Gate {
+        targets: g
+            .targets
+            .iter()
+            .map(|q| union.iter().position(|p| p == q).unwrap())
+            .collect(),
+        ..g.clone()
+    };
+    gates_commute(&local(a), &local(b), union.len())
+}
+
+impl<S: Scalar> Gate<S> {
+    pub fn new(mat: Mat<S>, targets: Vec<usize>) -> Option<Self> {
+        Self::try_new(mat, targets).ok()
+    }
+
+    /// Same as `new`, but tells why the gate is invalid.
+    pub fn try_new(mat: Mat<S>, targets: Vec<usize>) -> Result<Self, CircuitError> {
+        let power = 2usize.pow(targets.len() as u32);
+        if mat.ncols() != mat.nrows() || mat.ncols() != power {
+            return Err(CircuitError::InvalidDimension(mat.nrows(), mat.ncols()));
         }
         if !is_unit(&mat) {
-            return None;
+            return Err(CircuitError::NonUnitary);
         }
         let mut ts = HashSet::new();
         for target in &targets {
             if !ts.insert(*target) {
-                return None;
+                return Err(CircuitError::InvalidGate);
             }
         }
 
-        Some(Self { mat, targets })
+        Ok(Self::new_unchecked(mat, targets))
+    }
+
+    /// Same as `new`, without checking the size and unitarity of `mat` or
+    /// that the targets are distinct.
+    pub(crate) fn new_unchecked(mat: Mat<S>, targets: Vec<usize>) -> Self {
+        Self {
+            name: "unitary".to_string(),
+            mat,
+            targets,
+            controls: 0,
+            param: None,
+            label: None,
+            classical: None,
+        }
+    }
+
+    fn named(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Attaches a label shown by `draw` in place of the name, and as a
+    /// comment above the gate by `to_qasm`. Unlike the name, the label has
+    /// no effect on how the gate is exported or parsed.
+    pub fn with_label(mut self, label: String) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Checks whether the gate is a measurement or classically controlled,
+    /// see `Circuit::measure` and `Circuit::x_if`.
+    pub fn is_classical(&self) -> bool {
+        self.classical.is_some()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
     pub fn qubits(&self) -> &[usize] {
         &self.targets
     }
 
-    pub fn h(target: usize) -> Self {
-        let x = C::from_f64(1.0 / (2.0 as f64).sqrt());
-        Self::new(mat![[x, x], [x, -x]], vec![target]).unwrap()
+    /// Returns the qubits acting as controls.
+    pub fn controls(&self) -> &[usize] {
+        &self.targets[self.targets.len() - self.controls..]
     }
 
-    pub fn x(target: usize) -> Self {
-        Self::new(mat![[Z, ONE], [ONE, Z]], vec![target]).unwrap()
+    /// Returns the qubits the gate acts on, excluding its controls.
+    pub fn target_qubits(&self) -> &[usize] {
+        &self.targets[..self.targets.len() - self.controls]
     }
 
-    pub fn cx(control: usize, target: usize) -> Option<Self> {
-        Self::cnx(vec![control], target)
+    /// Returns the name of the gate without the `c` prefixes of its controls.
+    pub fn base_name(&self) -> &str {
+        self.name
+            .strip_prefix(&"c".repeat(self.controls))
+            .unwrap_or(&self.name)
     }
 
-    pub fn cnx(controls: Vec<usize>, target: usize) -> Option<Self> {
-        Self::x(target).controlled(controls)
+    pub fn matrix(&self) -> &Mat<S> {
+        &self.mat
     }
 
-    pub fn controlled(self, controls: Vec<usize>) -> Option<Gate> {
+    /// Builds the controlled version of `self`, active when every qubit in
+    /// `controls` is set.
+    ///
+    /// The resulting gate's targets are the original targets, in order,
+    /// followed by the controls, in order. Since bit `i` of the gate's matrix
+    /// index refers to `targets[i]`, the controls occupy the high bits and the
+    /// original matrix ends up in the bottom-right block. `turn_big` maps each
+    /// entry back to its actual wire, so the wire indices themselves can be in
+    /// any order (a control above or below the target behaves the same).
+    ///
+    /// Returns `None` if a control is repeated or is also one of the targets.
+    pub fn controlled(self, controls: Vec<usize>) -> Option<Gate<S>> {
+        let mut seen: HashSet<usize> = self.targets.iter().copied().collect();
+        if !controls.iter().all(|control| seen.insert(*control)) {
+            return None;
+        }
         let n = self.targets.len() + controls.len();
         let old_power = self.mat.ncols();
-        let power = (2 as u32).pow(n as u32) as usize;
+        let power = 2usize.pow(n as u32);
         let mut mat = Mat::identity(power, power);
         for j in 0..old_power {
             let new_j = power - old_power + j;
@@ -114,141 +689,2753 @@ impl Gate {
                 mat[(new_i, new_j)] = self.mat[(i, j)];
             }
         }
-        Self::new(mat, self.targets.into_iter().chain(controls).collect())
+        let name = format!("{}{}", "c".repeat(controls.len()), self.name);
+        let count = self.controls + controls.len();
+        let mut gate = Self::new(mat, self.targets.into_iter().chain(controls).collect())?;
+        gate.controls = count;
+        gate.param = self.param;
+        gate.label = self.label;
+        gate.classical = self.classical;
+        Some(gate.named(&name))
     }
 
-    pub fn turn_big(&self, n: usize) -> Mat<C> {
-        let power = (2 as u32).pow(n as u32) as usize;
-        let mut mat = Mat::zeros(power, power);
+    /// Returns the gate `controlled` was called on, i.e. the bottom-right
+    /// block of the matrix acting on `target_qubits`.
+    pub(crate) fn uncontrolled(&self) -> Gate<S> {
+        let power = 2usize.pow(self.target_qubits().len() as u32);
+        let offset = self.mat.nrows() - power;
+        Gate {
+            name: self.base_name().to_string(),
+            mat: Mat::from_fn(power, power, |i, j| self.mat[(offset + i, offset + j)]),
+            targets: self.target_qubits().to_vec(),
+            controls: 0,
+            param: self.param.clone(),
+            label: self.label.clone(),
+            classical: self.classical,
+        }
+    }
 
-        for row in 0..power {
-            for col in 0..power {
-                // Check if non-target qubits are identical between row and col
-                let mut non_target_bits_match = true;
-                for bit_pos in 0..n {
-                    if !self.targets.contains(&bit_pos) {
-                        let row_bit = (row >> bit_pos) & 1;
-                        let col_bit = (col >> bit_pos) & 1;
-                        if row_bit != col_bit {
-                            non_target_bits_match = false;
-                            break;
-                        }
-                    }
-                }
+    /// Checks whether every off-diagonal entry of the matrix is zero, within
+    /// the tolerance of `is_identity`.
+    pub fn is_diagonal(&self) -> bool {
+        let n = self.mat.nrows();
+        (0..n).all(|j| (0..n).all(|i| i == j || norm(self.mat[(i, j)]) <= TOLERANCE))
+    }
 
-                if !non_target_bits_match {
-                    continue; // stays 0
-                }
-                let mut small_row = 0;
-                let mut small_col = 0;
-                for (i, target_idx) in self.targets.iter().enumerate() {
-                    if (row >> target_idx) & 1 == 1 {
-                        small_row |= 1 << i;
-                    }
-                    if (col >> target_idx) & 1 == 1 {
-                        small_col |= 1 << i;
-                    }
-                }
+    /// Checks whether the gate only permutes basis states, like X, CX, SWAP
+    /// or the gates built by `from_permutation`, in which case `apply` moves
+    /// amplitudes around instead of multiplying them.
+    pub fn is_permutation(&self) -> bool {
+        kernel::permutation(self.mat.as_ref()).is_some()
+    }
 
-                mat[(row, col)] = self.mat[(small_row, small_col)];
-            }
+    /// Returns the inverse of the gate, i.e. the adjoint of its matrix on the
+    /// same qubits. A `dg` suffix is added to, or removed from, its name.
+    pub fn dagger(&self) -> Gate<S> {
+        let (rows, cols) = (self.mat.nrows(), self.mat.ncols());
+        let mat = Mat::from_fn(rows, cols, |i, j| {
+            let x = self.mat[(j, i)];
+            S::from_parts(x.real(), -x.imag())
+        });
+        let name = match self.name.strip_suffix("dg") {
+            Some(base) => base.to_string(),
+            None => format!("{}dg", self.name),
+        };
+        // The inverse of a rotation by θ is the rotation by -θ
+        let param = self.param.clone().map(|p| Unbound {
+            scale: -p.scale,
+            ..p
+        });
+        Gate {
+            name,
+            mat,
+            targets: self.targets.clone(),
+            controls: self.controls,
+            param,
+            label: self.label.clone(),
+            classical: self.classical,
         }
-        mat
     }
-}
 
-impl Circuit {
-    pub fn new(qubits: usize) -> Self {
-        Self {
-            gates: Vec::new(),
-            qubits,
+    /// Builds `base` controlled on every qubit of `controls`, whatever the
+    /// number of targets of `base`.
+    ///
+    /// Returns `None` if `controls` is empty, contains a repeated qubit or
+    /// shares a qubit with the targets of `base`.
+    pub fn multi_controlled(base: Gate<S>, controls: Vec<usize>) -> Option<Gate<S>> {
+        if controls.is_empty() {
+            return None;
         }
+        base.controlled(controls)
     }
 
-    pub fn get_vec(&self, i: usize) -> Option<Col<C>> {
-        let size = (2 as u32).pow(self.qubits as u32) as usize;
-        if i >= size {
-            return None;
+    /// Combines two gates acting on disjoint qubits into a single gate.
+    fn tensor(&self, other: &Gate<S>) -> Gate<S> {
+        Gate {
+            name: "fused".to_string(),
+            mat: kron(&other.mat, &self.mat),
+            targets: self.targets.iter().chain(&other.targets).copied().collect(),
+            controls: 0,
+            param: None,
+            label: None,
+            classical: None,
         }
-        let mut v = Col::zeros(size);
-        v[i] = ONE;
-        Some(v)
     }
 
-    pub fn h(&mut self, target: usize) -> Result<(), ()> {
-        if target >= self.qubits {
-            Err(())
-        } else {
-            self.gates.push(Gate::h(target));
-            Ok(())
+    /// Applies the gate in place to a statevector, without building the
+    /// `2^n x 2^n` matrix of `turn_big`.
+    ///
+    /// Only the amplitudes where every control is set are touched, and they
+    /// are updated in groups of `2^k` using the `2^k x 2^k` block acting on
+    /// the `k` non-control qubits.
+    pub fn apply(&self, state: &mut Col<S>) {
+        kernel::apply(
+            self.mat.as_ref(),
+            &self.targets,
+            self.controls,
+            state.as_mut(),
+        );
+    }
+
+    /// Cheap hash of the targets and matrix entries, used to find identical
+    /// gates without comparing every matrix.
+    fn fingerprint(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        self.targets.hash(&mut h);
+        for j in 0..self.mat.ncols() {
+            for i in 0..self.mat.nrows() {
+                self.mat[(i, j)].real().to_bits().hash(&mut h);
+                self.mat[(i, j)].imag().to_bits().hash(&mut h);
+            }
         }
+        h.finish()
     }
 
-    pub fn x(&mut self, target: usize) -> Result<(), ()> {
-        if target >= self.qubits {
-            Err(())
-        } else {
-            self.gates.push(Gate::x(target));
-            Ok(())
+    pub fn turn_big(&self, n: usize) -> Mat<S> {
+        #[cfg(test)]
+        EXPANSIONS.with(|count| count.set(count.get() + 1));
+        kernel::expand(self.mat.as_ref(), &self.targets, n)
+    }
+}
+
+impl Gate {
+    /// Loads a gate from a square `complex128` matrix saved with NumPy.
+    ///
+    /// Fails like `Gate::try_new` when the matrix doesn't fit `targets` or
+    /// isn't unitary.
+    pub fn from_npy(path: &Path, targets: Vec<usize>) -> Result<Gate, CircuitError> {
+        let mat = npy::read_matrix(path).map_err(|e| CircuitError::Npy(e.to_string()))?;
+        Gate::try_new(mat, targets)
+    }
+
+    /// Returns the eigenvalues of the gate's matrix and the matching
+    /// eigenvectors as the columns of the second element.
+    ///
+    /// Since gates are unitary, every eigenvalue lies on the unit circle.
+    pub fn eigen(&self) -> (Vec<C>, Mat<C>) {
+        let evd = self
+            .mat
+            .eigen()
+            .expect("eigendecomposition of a unitary matrix should converge");
+        let values = evd.S().column_vector().iter().copied().collect();
+        (values, evd.U().to_owned())
+    }
+
+    /// Converts the gate to another scalar backend.
+    pub fn cast<S: Scalar>(&self) -> Gate<S> {
+        Gate {
+            name: self.name.clone(),
+            mat: Mat::from_fn(self.mat.nrows(), self.mat.ncols(), |i, j| {
+                S::from_c(self.mat[(i, j)])
+            }),
+            targets: self.targets.clone(),
+            controls: self.controls,
+            param: self.param.clone(),
+            label: self.label.clone(),
+            classical: self.classical,
         }
     }
 
-    pub fn cx(&mut self, control: usize, target: usize) -> Result<(), ()> {
-        if target >= self.qubits || control >= self.qubits {
-            Err(())
-        } else {
-            let g = Gate::cx(control, target).ok_or(())?;
-            self.gates.push(g);
-            Ok(())
+    /// Builds `build(θ, target)`, or a placeholder gate to be rebuilt by
+    /// `Circuit::run_with` if `θ` is a named parameter.
+    fn rotation(theta: Parameter, build: fn(f64, usize) -> Gate, target: usize) -> Gate {
+        match theta {
+            Parameter::Value(theta) => build(theta, target),
+            Parameter::Param(name) => {
+                let mut gate = build(0.0, target);
+                gate.param = Some(Unbound {
+                    name,
+                    scale: 1.0,
+                    build,
+                });
+                gate
+            }
         }
     }
 
-    pub fn cnx(&mut self, controls: Vec<usize>, target: usize) -> Result<(), ()> {
-        if target >= self.qubits || controls.iter().any(|control| control >= &self.qubits) {
-            Err(())
+    /// Builds the product of three rotations about the axes named by
+    /// `convention`, one of `"ZYZ"`, `"XYX"` and `"ZXZ"`.
+    ///
+    /// `from_euler("ZYZ", (a, b, c), q)` is the matrix `Rz(a) Ry(b) Rz(c)`,
+    /// so `Rz(c)` acts first. Returns `None` for other conventions.
+    pub fn from_euler(convention: &str, angles: (f64, f64, f64), target: usize) -> Option<Self> {
+        let rotation = |axis: char, theta: f64| match axis {
+            'X' => Gate::rx(theta, target).mat,
+            'Y' => Gate::ry(theta, target).mat,
+            _ => Gate::rz(theta, target).mat,
+        };
+        let axes: Vec<char> = match convention {
+            "ZYZ" | "XYX" | "ZXZ" => convention.chars().collect(),
+            _ => return None,
+        };
+        let m =
+            rotation(axes[0], angles.0) * rotation(axes[1], angles.1) * rotation(axes[2], angles.2);
+        Some(Self::new(m, vec![target])?.named("euler"))
+    }
+
+    /// Writes a single-qubit gate as `e^{iα} Rz(β) Ry(γ) Rz(δ)`, returning
+    /// `(α, (β, γ, δ))` so the angles can be given back to `from_euler` with
+    /// the `"ZYZ"` convention.
+    ///
+    /// Returns `None` if the gate doesn't act on exactly one qubit.
+    pub fn zyz_decompose(&self) -> Option<(f64, (f64, f64, f64))> {
+        if self.targets.len() != 1 {
+            return None;
+        }
+        let m = &self.mat;
+        // det = e^{2iα}
+        let det = m[(0, 0)] * m[(1, 1)] - m[(0, 1)] * m[(1, 0)];
+        let alpha = phase_angle(det.real(), det.imag()) / 2.0;
+        let unphase = cplx(alpha.cos(), -alpha.sin());
+        let (v00, v10, v11) = (
+            m[(0, 0)] * unphase,
+            m[(1, 0)] * unphase,
+            m[(1, 1)] * unphase,
+        );
+        let gamma = 2.0 * norm(v10).atan2(norm(v00));
+        // v11 = e^{i(β+δ)/2} cos(γ/2) and v10 = e^{i(β-δ)/2} sin(γ/2), where
+        // only one of the two is meaningful if the other vanishes
+        let (sum, diff) = if norm(v00) < TOLERANCE {
+            (0.0, 2.0 * phase_angle(v10.real(), v10.imag()))
+        } else if norm(v10) < TOLERANCE {
+            (2.0 * phase_angle(v11.real(), v11.imag()), 0.0)
         } else {
-            let g = Gate::cnx(controls, target).ok_or(())?;
-            self.gates.push(g);
-            Ok(())
+            (
+                2.0 * phase_angle(v11.real(), v11.imag()),
+                2.0 * phase_angle(v10.real(), v10.imag()),
+            )
+        };
+        Some((alpha, ((sum + diff) / 2.0, gamma, (sum - diff) / 2.0)))
+    }
+
+    /// Builds a gate from its lowercase name, as used in OpenQASM, its
+    /// parameters and its qubits, e.g. `from_name("cx", &[], &[0, 1])`.
+    ///
+    /// Returns `None` if the name is unknown, the numbers of parameters or
+    /// qubits don't match the gate, or a qubit is repeated.
+    pub fn from_name(name: &str, params: &[f64], qubits: &[usize]) -> Option<Gate> {
+        match (name, params, qubits) {
+            ("h", [], [a]) => Some(Gate::h(*a)),
+            ("x", [], [a]) => Some(Gate::x(*a)),
+            ("y", [], [a]) => Some(Gate::y(*a)),
+            ("z", [], [a]) => Some(Gate::z(*a)),
+            ("s", [], [a]) => Some(Gate::s(*a)),
+            ("sdg", [], [a]) => Some(Gate::s(*a).dagger()),
+            ("t", [], [a]) => Some(Gate::t(*a)),
+            ("tdg", [], [a]) => Some(Gate::t(*a).dagger()),
+            ("sx", [], [a]) => Some(Gate::sx(*a)),
+            ("sxdg", [], [a]) => Some(Gate::sxdg(*a)),
+            ("p" | "u1", [lambda], [a]) => Some(Gate::p(*lambda, *a)),
+            ("rx", [theta], [a]) => Some(Gate::rx(*theta, *a)),
+            ("ry", [theta], [a]) => Some(Gate::ry(*theta, *a)),
+            ("rz", [theta], [a]) => Some(Gate::rz(*theta, *a)),
+            ("U" | "u3", [theta, phi, lambda], [a]) => Some(Gate::u(*theta, *phi, *lambda, *a)),
+            ("cx" | "CX", [], [c, t]) => Gate::cx(*c, *t),
+            ("cz", [], [c, t]) => Gate::cz(*c, *t),
+            ("ccx", [], [c1, c2, t]) => Gate::cnx(vec![*c1, *c2], *t),
+            ("ccz", [], [c1, c2, t]) => Gate::ccz(*c1, *c2, *t),
+            ("cp" | "cu1", [lambda], [c, t]) => Gate::cp(*lambda, *c, *t),
+            ("swap", [], [a, b]) => Gate::swap(*a, *b),
+            ("cswap", [], [c, a, b]) => Gate::fredkin(*c, *a, *b),
+            ("rxx", [theta], [a, b]) => Gate::rxx(*theta, *a, *b),
+            ("ryy", [theta], [a, b]) => Gate::ryy(*theta, *a, *b),
+            ("rzz", [theta], [a, b]) => Gate::rzz(*theta, *a, *b),
+            _ => None,
         }
     }
 
-    pub fn add_gate(&mut self, g: Gate) -> Result<(), ()> {
-        if g.qubits().iter().any(|x| x >= &self.qubits) {
-            Err(())
-        } else {
-            self.gates.push(g);
-            Ok(())
+    pub fn h(target: usize) -> Self {
+        let x = inv_sqrt2();
+        Self::new(mat![[x, x], [x, -x]], vec![target])
+            .unwrap()
+            .named("h")
+    }
+
+    pub fn x(target: usize) -> Self {
+        Self::new(mat![[Z, ONE], [ONE, Z]], vec![target])
+            .unwrap()
+            .named("x")
+    }
+
+    pub fn y(target: usize) -> Self {
+        Self::new(mat![[Z, -IM], [IM, Z]], vec![target])
+            .unwrap()
+            .named("y")
+    }
+
+    pub fn z(target: usize) -> Self {
+        Self::new(mat![[ONE, Z], [Z, -ONE]], vec![target])
+            .unwrap()
+            .named("z")
+    }
+
+    pub fn s(target: usize) -> Self {
+        Self::new(mat![[ONE, Z], [Z, IM]], vec![target])
+            .unwrap()
+            .named("s")
+    }
+
+    pub fn t(target: usize) -> Self {
+        let x = inv_sqrt2().re;
+        Self::new(mat![[ONE, Z], [Z, C::new(x, x)]], vec![target])
+            .unwrap()
+            .named("t")
+    }
+
+    /// Multiplies the whole state by `e^{iθ}`. The gate acts on no qubit.
+    pub fn global_phase(theta: f64) -> Self {
+        Self::new(mat![[cplx(theta.cos(), theta.sin())]], vec![])
+            .unwrap()
+            .named("global_phase")
+    }
+
+    /// OpenQASM's generic single-qubit gate
+    /// `U(θ, φ, λ) = e^{i(φ+λ)/2} Rz(φ) Ry(θ) Rz(λ)`, whose top-left entry is
+    /// always real.
+    pub fn u(theta: f64, phi: f64, lambda: f64, target: usize) -> Self {
+        let half = (phi + lambda) / 2.0;
+        let phase = cplx(half.cos(), half.sin());
+        let euler = Self::from_euler("ZYZ", (phi, theta, lambda), target).unwrap();
+        Self::new_unchecked(
+            Mat::from_fn(2, 2, |i, j| phase * euler.mat[(i, j)]),
+            vec![target],
+        )
+        .named("u3")
+    }
+
+    /// Phase gate `diag(1, e^{iλ})`, generalizing Z, S and T.
+    pub fn p(lambda: f64, target: usize) -> Self {
+        let phase = cplx(lambda.cos(), lambda.sin());
+        Self::new(mat![[ONE, Z], [Z, phase]], vec![target])
+            .unwrap()
+            .named("p")
+    }
+
+    /// Rotation `exp(-iθ/2 X)` about the X axis of the Bloch sphere.
+    pub fn rx(theta: f64, target: usize) -> Self {
+        let c = cplx((theta / 2.0).cos(), 0.0);
+        let s = cplx(0.0, -(theta / 2.0).sin());
+        Self::new(mat![[c, s], [s, c]], vec![target])
+            .unwrap()
+            .named("rx")
+    }
+
+    /// Rotation `exp(-iθ/2 Y)` about the Y axis of the Bloch sphere.
+    pub fn ry(theta: f64, target: usize) -> Self {
+        let (c, s) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+        Self::new(
+            mat![[cplx(c, 0.0), cplx(-s, 0.0)], [cplx(s, 0.0), cplx(c, 0.0)]],
+            vec![target],
+        )
+        .unwrap()
+        .named("ry")
+    }
+
+    /// Rotation `exp(-iθ/2 Z)` about the Z axis of the Bloch sphere, equal to
+    /// `P(θ)` up to a global phase.
+    pub fn rz(theta: f64, target: usize) -> Self {
+        let (c, s) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+        Self::new(mat![[cplx(c, -s), Z], [Z, cplx(c, s)]], vec![target])
+            .unwrap()
+            .named("rz")
+    }
+
+    /// `Rx(θ)` on `target`, controlled on every qubit of `controls`. With no
+    /// controls this is just `Rx(θ)`.
+    ///
+    /// Returns `None` if a control is repeated or is the target.
+    pub fn mcrx(theta: f64, controls: Vec<usize>, target: usize) -> Option<Self> {
+        Self::rx(theta, target).controlled(controls)
+    }
+
+    /// `Ry(θ)` on `target`, controlled on every qubit of `controls`. With no
+    /// controls this is just `Ry(θ)`.
+    ///
+    /// Returns `None` if a control is repeated or is the target.
+    pub fn mcry(theta: f64, controls: Vec<usize>, target: usize) -> Option<Self> {
+        Self::ry(theta, target).controlled(controls)
+    }
+
+    /// `Rz(θ)` on `target`, controlled on every qubit of `controls`. With no
+    /// controls this is just `Rz(θ)`.
+    ///
+    /// Returns `None` if a control is repeated or is the target.
+    pub fn mcrz(theta: f64, controls: Vec<usize>, target: usize) -> Option<Self> {
+        Self::rz(theta, target).controlled(controls)
+    }
+
+    /// Uniformly controlled gate applying `rotations[k]` to `target` when the
+    /// controls hold `k`, with `controls[0]` as its lowest bit. Only the
+    /// matrices of `rotations` are used, not their qubits.
+    ///
+    /// Returns `None` unless there are `2^controls.len()` single-qubit
+    /// rotations, or if a control is repeated or is the target.
+    pub fn uniformly_controlled(
+        rotations: Vec<Gate>,
+        controls: Vec<usize>,
+        target: usize,
+    ) -> Option<Self> {
+        if rotations.len() != 1 << controls.len() || rotations.iter().any(|r| r.mat.nrows() != 2) {
+            return None;
         }
+        let size = 2 * rotations.len();
+        let m = Mat::from_fn(size, size, |i, j| {
+            if i / 2 == j / 2 {
+                rotations[i / 2].mat[(i % 2, j % 2)]
+            } else {
+                Z
+            }
+        });
+        let mut targets = vec![target];
+        targets.extend(controls);
+        Some(Self::new(m, targets)?.named("ucg"))
     }
 
-    pub fn run(&self) -> Result<HashMap<String, C>, ()> {
-        let mut current = self.get_vec(0).ok_or(())?;
-        for gate in &self.gates {
-            let g = gate.turn_big(self.qubits);
-            let temp = g * current;
-            current = temp;
+    /// Controlled phase gate, applying `P(λ)` to `target` when `control` is
+    /// set.
+    pub fn cp(lambda: f64, control: usize, target: usize) -> Option<Self> {
+        Self::p(lambda, target).controlled(vec![control])
+    }
+
+    pub fn sx(target: usize) -> Self {
+        let (p, m) = (cplx(0.5, 0.5), cplx(0.5, -0.5));
+        Self::new(mat![[p, m], [m, p]], vec![target])
+            .unwrap()
+            .named("sx")
+    }
+
+    pub fn sxdg(target: usize) -> Self {
+        let (p, m) = (cplx(0.5, 0.5), cplx(0.5, -0.5));
+        Self::new(mat![[m, p], [p, m]], vec![target])
+            .unwrap()
+            .named("sxdg")
+    }
+
+    pub fn swap(a: usize, b: usize) -> Option<Self> {
+        let m = mat![
+            [ONE, Z, Z, Z],
+            [Z, Z, ONE, Z],
+            [Z, ONE, Z, Z],
+            [Z, Z, Z, ONE],
+        ];
+        Some(Self::new(m, vec![a, b])?.named("swap"))
+    }
+
+    /// Builds the gate sending basis state `|i⟩` of the targets to
+    /// `|perm[i]⟩`, e.g. to implement a classical reversible function.
+    ///
+    /// Returns `None` if `perm` isn't a bijection of `0..2^k` for `k` targets.
+    pub fn from_permutation(perm: &[usize], targets: Vec<usize>) -> Option<Self> {
+        let power = 2usize.pow(targets.len() as u32);
+        if perm.len() != power {
+            return None;
         }
-        let mut res = HashMap::new();
-        for (i, x) in current.iter().enumerate() {
-            let now = format!("{:0width$b}", i, width = self.qubits);
-            res.insert(now, *x);
+        let mut seen = vec![false; power];
+        for p in perm {
+            if *p >= power || seen[*p] {
+                return None;
+            }
+            seen[*p] = true;
         }
-        Ok(res)
+        let m = Mat::from_fn(power, power, |i, j| if perm[j] == i { ONE } else { Z });
+        Some(Self::new(m, targets)?.named("perm"))
     }
-}
 
-pub fn display_result(res: &HashMap<String, C>) {
-    let mut strs = res.keys().collect::<Vec<_>>();
-    strs.sort();
-    for s in strs {
-        println!(
-            "|{}⟩: {}{:.5} {} i{:.5}",
-            s,
-            if res[s].0 >= 0.0 { " " } else { "-" },
-            res[s].0.abs(),
-            if res[s].1 >= 0.0 { "+" } else { "-" },
-            res[s].1.abs()
-        );
+    /// Builds the diagonal gate on qubits `0..qubits` flipping the sign of
+    /// every basis state in `marked`, labelled like the keys of
+    /// `Circuit::run`, as used by Grover search.
+    ///
+    /// Returns `None` if a marked string isn't made of exactly `qubits`
+    /// characters 0 and 1.
+    pub fn phase_oracle(marked: &[String], qubits: usize) -> Option<Self> {
+        let power = 2usize.pow(qubits as u32);
+        let mut signs = vec![ONE; power];
+        for bits in marked {
+            if bits.len() != qubits || !bits.chars().all(|c| c == '0' || c == '1') {
+                return None;
+            }
+            let index = usize::from_str_radix(bits, 2).unwrap_or(0);
+            signs[index] = -ONE;
+        }
+        let m = Mat::from_fn(power, power, |i, j| if i == j { signs[i] } else { Z });
+        Some(Self::new(m, (0..qubits).collect())?.named("oracle"))
+    }
+
+    /// Ising XX interaction `exp(-iθ/2 X⊗X)`.
+    pub fn rxx(theta: f64, a: usize, b: usize) -> Option<Self> {
+        let c = cplx((theta / 2.0).cos(), 0.0);
+        let s = cplx(0.0, -(theta / 2.0).sin());
+        let m = mat![[c, Z, Z, s], [Z, c, s, Z], [Z, s, c, Z], [s, Z, Z, c]];
+        Some(Self::new(m, vec![a, b])?.named("rxx"))
+    }
+
+    /// Ising YY interaction `exp(-iθ/2 Y⊗Y)`.
+    pub fn ryy(theta: f64, a: usize, b: usize) -> Option<Self> {
+        let c = cplx((theta / 2.0).cos(), 0.0);
+        let s = cplx(0.0, (theta / 2.0).sin());
+        let m = mat![[c, Z, Z, s], [Z, c, -s, Z], [Z, -s, c, Z], [s, Z, Z, c]];
+        Some(Self::new(m, vec![a, b])?.named("ryy"))
+    }
+
+    /// Ising ZZ interaction `exp(-iθ/2 Z⊗Z)`.
+    pub fn rzz(theta: f64, a: usize, b: usize) -> Option<Self> {
+        let (c, s) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+        let (same, diff) = (cplx(c, -s), cplx(c, s));
+        let m = mat![
+            [same, Z, Z, Z],
+            [Z, diff, Z, Z],
+            [Z, Z, diff, Z],
+            [Z, Z, Z, same],
+        ];
+        Some(Self::new(m, vec![a, b])?.named("rzz"))
+    }
+
+    /// Controlled SWAP: exchanges `a` and `b` when `control` is set.
+    pub fn fredkin(control: usize, a: usize, b: usize) -> Option<Self> {
+        Self::swap(a, b)?.controlled(vec![control])
+    }
+
+    /// Doubly-controlled Z, flipping the sign of `|111⟩` only. It is
+    /// symmetric in its three qubits, so which ones are the controls doesn't
+    /// matter.
+    pub fn ccz(c1: usize, c2: usize, target: usize) -> Option<Self> {
+        Self::z(target).controlled(vec![c1, c2])
+    }
+
+    /// Margolus gate, a Toffoli correct up to a relative phase: it acts like
+    /// `ccx` except that `|c1 = 1, c2 = 0, target = 1⟩` picks up a sign of
+    /// `-1`.
+    ///
+    /// It takes three CX instead of six to decompose, and the phase is
+    /// harmless when the gate is later uncomputed with its inverse, e.g. when
+    /// computing a temporary AND into an ancilla.
+    pub fn margolus(c1: usize, c2: usize, target: usize) -> Option<Self> {
+        // Bit 0 of the index is the target, bits 1 and 2 the controls
+        let m = Mat::from_fn(8, 8, |i, j| {
+            let image = if j & 0b110 == 0b110 { j ^ 1 } else { j };
+            let sign = if j == 0b011 { -1.0 } else { 1.0 };
+            if i == image { cplx(sign, 0.0) } else { Z }
+        });
+        Some(Self::new(m, vec![target, c1, c2])?.named("margolus"))
+    }
+
+    pub fn cz(control: usize, target: usize) -> Option<Self> {
+        Self::z(target).controlled(vec![control])
+    }
+
+    pub fn cx(control: usize, target: usize) -> Option<Self> {
+        Self::cnx(vec![control], target)
+    }
+
+    pub fn cnx(controls: Vec<usize>, target: usize) -> Option<Self> {
+        Self::x(target).controlled(controls)
+    }
+}
+
+impl<S> fmt::Debug for Gate<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gate")
+            .field("name", &self.name)
+            .field("targets", &self.targets)
+            .finish()
+    }
+}
+
+impl<S> fmt::Debug for Circuit<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Circuit")
+            .field("qubits", &self.qubits)
+            .field("gate_count", &self.gates.len())
+            .field("gates", &self.gates)
+            .finish()
+    }
+}
+
+/// Concatenates two circuits, applying the gates of the left one first.
+///
+/// # Panics
+///
+/// Panics if the circuits act on different numbers of qubits; use
+/// `Circuit::append` to get an error instead.
+impl<S: Scalar> Add for Circuit<S> {
+    type Output = Circuit<S>;
+
+    fn add(mut self, other: Circuit<S>) -> Circuit<S> {
+        if let Err(e) = self.append(&other) {
+            panic!("{}", e);
+        }
+        self
+    }
+}
+
+impl Circuit {
+    pub fn new(qubits: usize) -> Self {
+        Self {
+            gates: Vec::new(),
+            qubits,
+            registers: Vec::new(),
+            strategy: Strategy::Dense,
+            free_ancillas: Vec::new(),
+            tolerance: TOLERANCE,
+        }
+    }
+
+    /// Creates a circuit made of named registers of the given sizes, laid out
+    /// one after the other in the flat qubit numbering.
+    pub fn with_registers(regs: &[(String, usize)]) -> Self {
+        Self {
+            gates: Vec::new(),
+            qubits: regs.iter().map(|(_, size)| size).sum(),
+            registers: regs.to_vec(),
+            strategy: Strategy::Dense,
+            free_ancillas: Vec::new(),
+            tolerance: TOLERANCE,
+        }
+    }
+
+    /// Builds a circuit from `(name, params, qubits)` operations, see
+    /// `Gate::from_name` for the available names.
+    pub fn from_ops(
+        qubits: usize,
+        ops: &[(String, Vec<f64>, Vec<usize>)],
+    ) -> Result<Self, CircuitError> {
+        let mut circuit = Circuit::new(qubits);
+        for (name, params, targets) in ops {
+            let gate = Gate::from_name(name, params, targets).ok_or_else(|| {
+                CircuitError::UnknownGate(name.clone(), params.len(), targets.len())
+            })?;
+            circuit.add_gate(gate)?;
+        }
+        Ok(circuit)
+    }
+
+    /// Builds the oracle `|x⟩|y⟩ ↦ |x⟩|y ⊕ f(x)⟩` of a classical function as
+    /// a single permutation gate, with `x` on qubits `0..input_bits` and `y`
+    /// on the next `output_bits` qubits. `f` gets the bits of `x` with qubit
+    /// 0 first, and returns the bits of `f(x)` in the same order.
+    ///
+    /// Fails with `CircuitError::InvalidBits` if `f` returns the wrong number
+    /// of bits, and with `CircuitError::TooManyQubits` if the gate's matrix
+    /// is too large, see `MAX_QUBITS`.
+    pub fn from_boolean<F: Fn(&[bool]) -> Vec<bool>>(
+        input_bits: usize,
+        output_bits: usize,
+        f: F,
+    ) -> Result<Self, CircuitError> {
+        let qubits = input_bits + output_bits;
+        if 2 * qubits > MAX_QUBITS {
+            return Err(CircuitError::TooManyQubits(qubits));
+        }
+        let outputs = (0..1usize << input_bits)
+            .map(|x| {
+                let bits: Vec<bool> = (0..input_bits).map(|q| (x >> q) & 1 == 1).collect();
+                let out = f(&bits);
+                if out.len() != output_bits {
+                    let label = out.iter().map(|b| if *b { '1' } else { '0' }).collect();
+                    return Err(CircuitError::InvalidBits(label));
+                }
+                Ok(out
+                    .iter()
+                    .enumerate()
+                    .fold(0, |acc, (j, b)| acc | (*b as usize) << j))
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        let mask = (1 << input_bits) - 1;
+        let perm: Vec<usize> = (0..1usize << qubits)
+            .map(|i| i ^ outputs[i & mask] << input_bits)
+            .collect();
+        let mut circuit = Circuit::new(qubits);
+        circuit.add_gate(Gate::from_permutation(&perm, (0..qubits).collect()).unwrap())?;
+        Ok(circuit)
+    }
+
+    /// Builds a circuit taking `|0...0⟩` to the state with the given
+    /// amplitudes, renormalized, with amplitude `k` on basis state `|k⟩`.
+    ///
+    /// Qubits are set from the highest down, each with a uniformly
+    /// controlled `Ry` on the qubits above it splitting the weight of the
+    /// remaining amplitudes. The relative phases are applied together with
+    /// the last rotation, on qubit 0.
+    pub fn prepare_amplitudes(amplitudes: &[C]) -> Result<Self, CircuitError> {
+        let len = amplitudes.len();
+        if !len.is_power_of_two() {
+            return Err(CircuitError::InvalidDimension(len, 1));
+        }
+        if amplitudes.iter().all(|a| norm(*a) < 1E-10) {
+            return Err(CircuitError::StateCollapsed);
+        }
+        let qubits = len.trailing_zeros() as usize;
+        let mut circuit = Circuit::new(qubits);
+        if qubits == 0 {
+            let a = amplitudes[0];
+            circuit.global_phase(phase_angle(a.real(), a.imag()))?;
+            return Ok(circuit);
+        }
+        let weight = |from: usize, to: usize| {
+            amplitudes[from..to]
+                .iter()
+                .map(|a| norm(*a).powi(2))
+                .sum::<f64>()
+                .sqrt()
+        };
+        for q in (0..qubits).rev() {
+            let block = 2 << q;
+            let rotations = (0..len / block)
+                .map(|k| {
+                    let start = k * block;
+                    let theta = 2.0
+                        * weight(start + block / 2, start + block)
+                            .atan2(weight(start, start + block / 2));
+                    let ry = Gate::ry(theta, q);
+                    if q > 0 {
+                        return ry;
+                    }
+                    let phase = |a: C| {
+                        let angle = phase_angle(a.real(), a.imag());
+                        cplx(angle.cos(), angle.sin())
+                    };
+                    let (p0, p1) = (phase(amplitudes[start]), phase(amplitudes[start + 1]));
+                    let m =
+                        Mat::from_fn(2, 2, |i, j| ry.mat[(i, j)] * if i == 0 { p0 } else { p1 });
+                    Gate::new(m, vec![q]).unwrap()
+                })
+                .collect();
+            let controls = (q + 1..qubits).collect();
+            circuit.add_gate(Gate::uniformly_controlled(rotations, controls, q).unwrap())?;
+        }
+        Ok(circuit)
+    }
+
+    /// Exports the circuit as an OpenQASM 3 program, see `qasm::emit`.
+    pub fn to_qasm(&self) -> Result<String, CircuitError> {
+        qasm::emit(self)
+    }
+
+    /// Imports an OpenQASM program, see the `qasm` module for the supported
+    /// subset.
+    pub fn from_qasm(src: &str) -> Result<Self, CircuitError> {
+        qasm::parse(src)
+    }
+
+    /// Builds the quantum Fourier transform on `qubits` qubits, sending
+    /// `|x⟩` to `Σ_k e^{2πi xk / 2^n} |k⟩ / √(2^n)`.
+    pub fn qft(qubits: usize) -> Self {
+        let mut c = Self::qft_no_swap(qubits);
+        for q in 0..qubits / 2 {
+            c.swap(q, qubits - 1 - q).unwrap();
+        }
+        c
+    }
+
+    /// Same as `qft` without the final swaps, so the output is the QFT with
+    /// the order of the qubits reversed: qubit `q` holds what `qft` puts on
+    /// qubit `n - 1 - q`.
+    pub fn qft_no_swap(qubits: usize) -> Self {
+        let mut c = Self::new(qubits);
+        for target in (0..qubits).rev() {
+            c.h(target).unwrap();
+            for control in (0..target).rev() {
+                let lambda = std::f64::consts::PI / 2f64.powi((target - control) as i32);
+                c.cp(lambda, control, target).unwrap();
+            }
+        }
+        c
+    }
+
+    /// Simulates the circuit with the stabilizer formalism and returns the
+    /// probability of every measurement outcome that can occur.
+    ///
+    /// Only works for circuits made of H, S, X, Z and CX gates, but runs in
+    /// polynomial time in the number of qubits.
+    pub fn run_stabilizer(&self) -> Result<HashMap<String, f64>, CircuitError> {
+        Ok(StabilizerSim::from_circuit(self)?.probabilities())
+    }
+}
+
+impl<S: Scalar> Circuit<S> {
+    /// Same as `Circuit::new`, with amplitudes stored as `S` instead of `C`.
+    pub fn with_scalar(qubits: usize) -> Self {
+        Self {
+            gates: Vec::new(),
+            qubits,
+            registers: Vec::new(),
+            strategy: Strategy::Dense,
+            free_ancillas: Vec::new(),
+            tolerance: TOLERANCE,
+        }
+    }
+
+    /// Sets how far from exact `run_checked`, `is_unitary` and `equiv` let
+    /// results be, `TOLERANCE` by default. Long circuits can accumulate
+    /// rounding errors past the default.
+    ///
+    /// The same tolerance is the smallest probability `measure_and_project`,
+    /// `measure_some`, `run_shot` and `sample_shots` accept as non-zero, the
+    /// norm below which `run` reports a collapsed state, and how much weight
+    /// `free_ancilla` lets an ancilla keep on `|1⟩`.
+    pub fn set_tolerance(&mut self, tol: f64) {
+        self.tolerance = tol;
+    }
+
+    pub fn tolerance(&self) -> f64 {
+        self.tolerance
+    }
+
+    /// Returns the flat index of qubit `offset` of register `name`.
+    pub fn qubit(&self, name: &str, offset: usize) -> Result<usize, CircuitError> {
+        let mut start = 0;
+        for (reg, size) in &self.registers {
+            if reg == name {
+                if offset >= *size {
+                    return Err(CircuitError::QubitOutOfRange(offset));
+                }
+                return Ok(start + offset);
+            }
+            start += size;
+        }
+        Err(CircuitError::UnknownRegister(name.to_string()))
+    }
+
+    /// Returns a circuit on the same register holding `gates`.
+    fn with_gates(&self, gates: Vec<Gate<S>>) -> Circuit<S> {
+        Circuit {
+            qubits: self.qubits,
+            gates,
+            registers: self.registers.clone(),
+            strategy: self.strategy,
+            free_ancillas: self.free_ancillas.clone(),
+            tolerance: self.tolerance,
+        }
+    }
+
+    pub fn get_vec(&self, i: usize) -> Option<Col<S>> {
+        let size = 2usize.pow(self.qubits as u32);
+        if i >= size {
+            return None;
+        }
+        let mut v = Col::zeros(size);
+        v[i] = S::from_parts(1.0, 0.0);
+        Some(v)
+    }
+
+    pub fn num_qubits(&self) -> usize {
+        self.qubits
+    }
+
+    /// Sets how `run` and `statevector` apply the gates.
+    pub fn set_apply_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Returns the gates in the order they were added, which is also the
+    /// order they act in: the first gate is applied to `|0...0⟩` first.
+    pub fn gates(&self) -> &[Gate<S>] {
+        &self.gates
+    }
+
+    /// Returns a scratch qubit in `|0⟩`, reusing one released by
+    /// `free_ancilla` or growing the register by one qubit.
+    ///
+    /// Gates refer to qubits by index, so the existing gates are unaffected
+    /// by the extra qubit.
+    pub fn alloc_ancilla(&mut self) -> usize {
+        self.free_ancillas.pop().unwrap_or_else(|| {
+            self.qubits += 1;
+            self.qubits - 1
+        })
+    }
+
+    /// Releases an ancilla so `alloc_ancilla` can hand it out again.
+    ///
+    /// Fails with `CircuitError::DirtyAncilla` unless the gates so far leave
+    /// it in `|0⟩`, unentangled from the other qubits.
+    pub fn free_ancilla(&mut self, index: usize) -> Result<(), CircuitError> {
+        self.check_qubit(index)?;
+        let state = self.statevector()?;
+        let excited: f64 = (0..state.nrows())
+            .filter(|i| (i >> index) & 1 == 1)
+            .map(|i| norm(state[i]) * norm(state[i]))
+            .sum();
+        if excited > self.tolerance {
+            return Err(CircuitError::DirtyAncilla(index));
+        }
+        self.free_ancillas.push(index);
+        Ok(())
+    }
+
+    /// Returns the names of the gates in application order, see `gates`.
+    pub fn gate_order(&self) -> Vec<&str> {
+        self.gates.iter().map(|g| g.name()).collect()
+    }
+
+    fn check_qubit(&self, qubit: usize) -> Result<(), CircuitError> {
+        if qubit >= self.qubits {
+            Err(CircuitError::QubitOutOfRange(qubit))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn h(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::h(target).cast());
+        Ok(())
+    }
+
+    pub fn x(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::x(target).cast());
+        Ok(())
+    }
+
+    pub fn z(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::z(target).cast());
+        Ok(())
+    }
+
+    pub fn s(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::s(target).cast());
+        Ok(())
+    }
+
+    pub fn t(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::t(target).cast());
+        Ok(())
+    }
+
+    pub fn global_phase(&mut self, theta: f64) -> Result<(), CircuitError> {
+        self.gates.push(Gate::global_phase(theta).cast());
+        Ok(())
+    }
+
+    pub fn p(&mut self, lambda: f64, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::p(lambda, target).cast());
+        Ok(())
+    }
+
+    /// Adds `Rx(θ)`, where `theta` can be a named parameter bound by
+    /// `run_with`.
+    pub fn rx(&mut self, theta: impl Into<Parameter>, target: usize) -> Result<(), CircuitError> {
+        self.add_gate(Gate::rotation(theta.into(), Gate::rx, target).cast())
+    }
+
+    /// Adds `Ry(θ)`, see `rx`.
+    pub fn ry(&mut self, theta: impl Into<Parameter>, target: usize) -> Result<(), CircuitError> {
+        self.add_gate(Gate::rotation(theta.into(), Gate::ry, target).cast())
+    }
+
+    /// Adds `Rz(θ)`, see `rx`.
+    pub fn rz(&mut self, theta: impl Into<Parameter>, target: usize) -> Result<(), CircuitError> {
+        self.add_gate(Gate::rotation(theta.into(), Gate::rz, target).cast())
+    }
+
+    pub fn sx(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::sx(target).cast());
+        Ok(())
+    }
+
+    pub fn sxdg(&mut self, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        self.gates.push(Gate::sxdg(target).cast());
+        Ok(())
+    }
+
+    pub fn cx(&mut self, control: usize, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(control)?;
+        self.check_qubit(target)?;
+        let g = Gate::cx(control, target).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn cz(&mut self, control: usize, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(control)?;
+        self.check_qubit(target)?;
+        let g = Gate::cz(control, target).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) -> Result<(), CircuitError> {
+        self.check_qubit(a)?;
+        self.check_qubit(b)?;
+        let g = Gate::swap(a, b).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn cp(&mut self, lambda: f64, control: usize, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(control)?;
+        self.check_qubit(target)?;
+        let g = Gate::cp(lambda, control, target).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn rxx(&mut self, theta: f64, a: usize, b: usize) -> Result<(), CircuitError> {
+        self.check_qubit(a)?;
+        self.check_qubit(b)?;
+        let g = Gate::rxx(theta, a, b).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn ryy(&mut self, theta: f64, a: usize, b: usize) -> Result<(), CircuitError> {
+        self.check_qubit(a)?;
+        self.check_qubit(b)?;
+        let g = Gate::ryy(theta, a, b).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn rzz(&mut self, theta: f64, a: usize, b: usize) -> Result<(), CircuitError> {
+        self.check_qubit(a)?;
+        self.check_qubit(b)?;
+        let g = Gate::rzz(theta, a, b).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn fredkin(&mut self, control: usize, a: usize, b: usize) -> Result<(), CircuitError> {
+        self.check_qubit(control)?;
+        self.check_qubit(a)?;
+        self.check_qubit(b)?;
+        let g = Gate::fredkin(control, a, b).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn ccz(&mut self, c1: usize, c2: usize, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(c1)?;
+        self.check_qubit(c2)?;
+        self.check_qubit(target)?;
+        let g = Gate::ccz(c1, c2, target).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    pub fn cnx(&mut self, controls: Vec<usize>, target: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        for control in &controls {
+            self.check_qubit(*control)?;
+        }
+        let g = Gate::cnx(controls, target).ok_or(CircuitError::InvalidGate)?;
+        self.gates.push(g.cast());
+        Ok(())
+    }
+
+    /// Measures `target` mid-circuit into the classical bit `cbit`. Circuits
+    /// with measurements are simulated by `run_shot`.
+    pub fn measure(&mut self, target: usize, cbit: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        let mut g = Gate::new_unchecked(Mat::identity(2, 2), vec![target]).named("measure");
+        g.classical = Some(Classical::Measure(cbit));
+        self.gates.push(g);
+        Ok(())
+    }
+
+    /// Applies X to `target` only if the classical bit `cbit`, written by an
+    /// earlier `measure`, is 1.
+    pub fn x_if(&mut self, target: usize, cbit: usize) -> Result<(), CircuitError> {
+        self.check_qubit(target)?;
+        let mut g: Gate<S> = Gate::x(target).cast();
+        g.classical = Some(Classical::If(cbit));
+        self.gates.push(g);
+        Ok(())
+    }
+
+    pub fn add_gate(&mut self, g: Gate<S>) -> Result<(), CircuitError> {
+        for q in g.qubits() {
+            self.check_qubit(*q)?;
+        }
+        self.gates.push(g);
+        Ok(())
+    }
+
+    /// Builds a gate applying `mat` to `targets` and adds it.
+    pub fn unitary_gate(&mut self, mat: Mat<S>, targets: Vec<usize>) -> Result<(), CircuitError> {
+        for q in &targets {
+            self.check_qubit(*q)?;
+        }
+        self.gates.push(Gate::try_new(mat, targets)?);
+        Ok(())
+    }
+
+    /// Adds every gate of `gates`, or none of them if any gate acts on a qubit
+    /// outside of the circuit.
+    pub fn add_gates(&mut self, gates: Vec<Gate<S>>) -> Result<(), CircuitError> {
+        for g in &gates {
+            for q in g.qubits() {
+                self.check_qubit(*q)?;
+            }
+        }
+        self.gates.extend(gates);
+        Ok(())
+    }
+
+    /// Adds `gate_fn(q)` for every qubit `q`, e.g. `apply_all(Gate::h)` to
+    /// put every qubit in superposition.
+    pub fn apply_all(&mut self, gate_fn: impl Fn(usize) -> Gate) -> Result<(), CircuitError> {
+        self.add_gates((0..self.qubits).map(|q| gate_fn(q).cast()).collect())
+    }
+
+    /// Adds `gate_fn(a, b)` for every pair `(a, b)` of `pairs`, e.g. to build
+    /// entangling layers. Like `add_gates`, either every gate is added or
+    /// none of them.
+    pub fn apply_pairs(
+        &mut self,
+        gate_fn: impl Fn(usize, usize) -> Option<Gate>,
+        pairs: &[(usize, usize)],
+    ) -> Result<(), CircuitError> {
+        let gates = pairs
+            .iter()
+            .map(|(a, b)| gate_fn(*a, *b).map(|g| g.cast()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(CircuitError::InvalidGate)?;
+        self.add_gates(gates)
+    }
+
+    /// Adds the gates of `other` after the gates of `self`.
+    pub fn append(&mut self, other: &Circuit<S>) -> Result<(), CircuitError> {
+        if other.qubits != self.qubits {
+            return Err(CircuitError::QubitCountMismatch(self.qubits, other.qubits));
+        }
+        self.gates.extend(other.gates.iter().cloned());
+        Ok(())
+    }
+
+    /// Returns a circuit applying the gates of `self` `times` times in a row.
+    ///
+    /// `repeat(0)` gives the empty circuit on the same register.
+    pub fn repeat(&self, times: usize) -> Circuit<S> {
+        let gates = (0..times)
+            .flat_map(|_| self.gates.iter().cloned())
+            .collect();
+        self.with_gates(gates)
+    }
+
+    /// Returns the circuit obtained by pinning each qubit of `fixed` to a
+    /// classical value, 0 or 1, and removing it from the register.
+    ///
+    /// Pinned qubits may only be used as controls: gates with a control
+    /// pinned to 0 never fire and are dropped, and controls pinned to 1 are
+    /// always satisfied and removed from their gate. The remaining qubits
+    /// keep their relative order.
+    pub fn with_fixed(&self, fixed: &HashMap<usize, usize>) -> Result<Circuit<S>, CircuitError> {
+        for (q, value) in fixed {
+            self.check_qubit(*q)?;
+            if *value > 1 {
+                return Err(CircuitError::InvalidBits(value.to_string()));
+            }
+        }
+        let kept: Vec<usize> = (0..self.qubits)
+            .filter(|q| !fixed.contains_key(q))
+            .collect();
+        let remap = |q: &usize| kept.binary_search(q).unwrap();
+        let mut reduced = Circuit::with_scalar(kept.len());
+        reduced.strategy = self.strategy;
+        for gate in &self.gates {
+            if let Some(q) = gate.target_qubits().iter().find(|q| fixed.contains_key(q)) {
+                return Err(CircuitError::FixedQubitTargeted(*q));
+            }
+            if gate.controls().iter().any(|q| fixed.get(q) == Some(&0)) {
+                continue;
+            }
+            let mut base = gate.uncontrolled();
+            base.targets = base.targets.iter().map(remap).collect();
+            let controls = gate
+                .controls()
+                .iter()
+                .filter(|q| !fixed.contains_key(q))
+                .map(remap)
+                .collect();
+            let reduced_gate = base.controlled(controls).ok_or(CircuitError::InvalidGate)?;
+            reduced.gates.push(reduced_gate);
+        }
+        Ok(reduced)
+    }
+
+    /// Rewrites every gate whose name isn't in `basis` using built-in rules,
+    /// e.g. CX into `["h", "cz"]` or Toffoli into `["h", "t", "cx"]`.
+    ///
+    /// Rules are applied again to the gates they produce, and a gate fails
+    /// with `CircuitError::NoDecomposition` if no chain of rules reaches the
+    /// basis.
+    pub fn transpile(&self, basis: &[&str]) -> Result<Circuit<S>, CircuitError> {
+        // Deep enough for ccx -> tdg -> t, while stopping cycles like cx <-> cz
+        const MAX_DEPTH: usize = 4;
+        fn rewrite<S: Scalar>(
+            gate: &Gate<S>,
+            basis: &[&str],
+            depth: usize,
+            out: &mut Vec<Gate<S>>,
+        ) -> Result<(), CircuitError> {
+            if basis.contains(&gate.name()) {
+                out.push(gate.clone());
+                return Ok(());
+            }
+            let parts = decomposition(gate.name(), gate.qubits())
+                .filter(|_| depth < MAX_DEPTH)
+                .ok_or_else(|| CircuitError::NoDecomposition(gate.name().to_string()))?;
+            for part in parts {
+                rewrite(&part.cast(), basis, depth + 1, out)?;
+            }
+            Ok(())
+        }
+
+        let mut gates = Vec::new();
+        for gate in &self.gates {
+            rewrite(gate, basis, 0, &mut gates)?;
+        }
+        Ok(self.with_gates(gates))
+    }
+
+    /// Checks that every two-qubit gate acts on a pair of qubits listed in
+    /// `coupling`, in either order, as required to run it on hardware with
+    /// this connectivity.
+    ///
+    /// Gates on more than two qubits are never allowed and must be
+    /// decomposed first, e.g. with `transpile`. Fails with
+    /// `CircuitError::Uncoupled` for the first gate breaking the rule.
+    pub fn check_connectivity(&self, coupling: &[(usize, usize)]) -> Result<(), CircuitError> {
+        for (i, gate) in self.gates.iter().enumerate() {
+            let allowed = match gate.qubits() {
+                [a, b] => coupling.contains(&(*a, *b)) || coupling.contains(&(*b, *a)),
+                qubits => qubits.len() < 2,
+            };
+            if !allowed {
+                return Err(CircuitError::Uncoupled(i, gate.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts SWAP gates so every two-qubit gate acts on qubits coupled in
+    /// `coupling`, see `route_with_layout`.
+    pub fn route(&self, coupling: &[(usize, usize)]) -> Result<Circuit<S>, CircuitError> {
+        Ok(self.route_with_layout(coupling)?.0)
+    }
+
+    /// Same as `route`, also returning the final layout: logical qubit `l`
+    /// of `self` ends up on qubit `layout[l]` of the routed circuit.
+    ///
+    /// Before each gate on uncoupled qubits, its first qubit is swapped
+    /// along a shortest path in the coupling graph until it is next to the
+    /// second one. Swaps aren't undone, so the routed circuit matches `self`
+    /// up to this permutation of the qubits.
+    pub fn route_with_layout(
+        &self,
+        coupling: &[(usize, usize)],
+    ) -> Result<(Circuit<S>, Vec<usize>), CircuitError> {
+        let mut neighbours = vec![Vec::new(); self.qubits];
+        for (a, b) in coupling {
+            self.check_qubit(*a)?;
+            self.check_qubit(*b)?;
+            neighbours[*a].push(*b);
+            neighbours[*b].push(*a);
+        }
+        // Breadth-first search for the path from `from` to `to`, excluding
+        // `from`
+        let path = |from: usize, to: usize| -> Option<Vec<usize>> {
+            let mut previous = vec![None; self.qubits];
+            let mut queue = VecDeque::from([from]);
+            previous[from] = Some(from);
+            while let Some(q) = queue.pop_front() {
+                if q == to {
+                    let mut path = vec![to];
+                    while let Some(p) = previous[path[path.len() - 1]].filter(|p| *p != from) {
+                        path.push(p);
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                for n in &neighbours[q] {
+                    if previous[*n].is_none() {
+                        previous[*n] = Some(q);
+                        queue.push_back(*n);
+                    }
+                }
+            }
+            None
+        };
+
+        let mut layout: Vec<usize> = (0..self.qubits).collect();
+        let mut gates = Vec::new();
+        for (i, gate) in self.gates.iter().enumerate() {
+            let uncoupled = || CircuitError::Uncoupled(i, gate.name.clone());
+            match gate.qubits() {
+                [a, b] => {
+                    let route = path(layout[*a], layout[*b]).ok_or_else(uncoupled)?;
+                    // Every step but the last one brings `a` closer to `b`
+                    for next in &route[..route.len() - 1] {
+                        let here = layout[*a];
+                        gates.push(Gate::swap(here, *next).unwrap().cast());
+                        let other = layout.iter().position(|p| p == next).unwrap();
+                        layout[other] = here;
+                        layout[*a] = *next;
+                    }
+                }
+                qubits if qubits.len() > 2 => return Err(uncoupled()),
+                _ => {}
+            }
+            let mut routed = gate.clone();
+            routed.targets = gate.targets.iter().map(|q| layout[*q]).collect();
+            gates.push(routed);
+        }
+        Ok((self.with_gates(gates), layout))
+    }
+
+    /// Returns the phase polynomial of the circuit if every gate is diagonal
+    /// in the computational basis, like Z, S, T, CZ or CCZ, and `None`
+    /// otherwise. See `phase_poly_runs` for circuits that are only partly
+    /// diagonal.
+    ///
+    /// The phases are read off each basis state in `O(gates 2^n)` time and
+    /// split over parities with a Walsh-Hadamard transform in `O(n 2^n)`.
+    /// Circuits on more than `MAX_QUBITS` qubits also give `None`.
+    pub fn extract_phase_poly(&self) -> Option<PhasePoly> {
+        if self.qubits > MAX_QUBITS || !self.gates.iter().all(Self::is_phase_gate) {
+            return None;
+        }
+        Some(self.phase_poly(&self.gates))
+    }
+
+    /// Splits the circuit into maximal contiguous runs of diagonal gates and
+    /// returns the range of gate indices of each run with its phase
+    /// polynomial, in circuit order. Gates outside the ranges are left as is.
+    ///
+    /// Circuits on more than `MAX_QUBITS` qubits give no runs.
+    pub fn phase_poly_runs(&self) -> Vec<(Range<usize>, PhasePoly)> {
+        let mut runs = Vec::new();
+        if self.qubits > MAX_QUBITS {
+            return runs;
+        }
+        let mut start = 0;
+        while start < self.gates.len() {
+            if !Self::is_phase_gate(&self.gates[start]) {
+                start += 1;
+                continue;
+            }
+            let end = self.gates[start..]
+                .iter()
+                .position(|g| !Self::is_phase_gate(g))
+                .map_or(self.gates.len(), |len| start + len);
+            runs.push((start..end, self.phase_poly(&self.gates[start..end])));
+            start = end;
+        }
+        runs
+    }
+
+    fn is_phase_gate(gate: &Gate<S>) -> bool {
+        gate.param.is_none() && gate.classical.is_none() && gate.is_diagonal()
+    }
+
+    fn phase_poly(&self, gates: &[Gate<S>]) -> PhasePoly {
+        let size = 1usize << self.qubits;
+        let mut coeffs: Vec<f64> = (0..size)
+            .map(|x| {
+                gates
+                    .iter()
+                    .map(|g| {
+                        let k = g
+                            .targets
+                            .iter()
+                            .enumerate()
+                            .fold(0, |acc, (bit, q)| acc | ((x >> q) & 1) << bit);
+                        let d = g.mat[(k, k)];
+                        phase_angle(d.real(), d.imag())
+                    })
+                    .sum()
+            })
+            .collect();
+        let global = coeffs[0];
+
+        // f(x) = Σ_S c_S (-1)^{S·x} and (-1)^{S·x} = 1 - 2 (S·x mod 2)
+        let mut half = 1;
+        while half < size {
+            for i in (0..size).step_by(2 * half) {
+                for j in i..i + half {
+                    let (a, b) = (coeffs[j], coeffs[j + half]);
+                    coeffs[j] = a + b;
+                    coeffs[j + half] = a - b;
+                }
+            }
+            half *= 2;
+        }
+        let terms = coeffs
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(mask, c)| (mask, -2.0 * c / size as f64))
+            .filter(|(_, angle)| {
+                let wrapped = angle.rem_euclid(2.0 * std::f64::consts::PI);
+                wrapped > 1E-10 && 2.0 * std::f64::consts::PI - wrapped > 1E-10
+            })
+            .collect();
+        PhasePoly {
+            qubits: self.qubits,
+            global,
+            terms,
+        }
+    }
+
+    /// Adds SWAP gates between neighbouring qubits moving the state of qubit
+    /// `target_order[i]` onto qubit `i`, as needed on a linear topology.
+    ///
+    /// The swaps are those of a bubble sort, so their number is the number
+    /// of inversions of `target_order`, the fewest possible with
+    /// nearest-neighbour swaps.
+    pub fn swap_network(&mut self, target_order: &[usize]) -> Result<(), CircuitError> {
+        let mut sorted = target_order.to_vec();
+        sorted.sort_unstable();
+        if sorted != (0..self.qubits).collect::<Vec<_>>() {
+            return Err(CircuitError::InvalidPermutation(target_order.to_vec()));
+        }
+        // rank[q] is the position the state of qubit q must reach
+        let mut rank = vec![0; self.qubits];
+        for (i, q) in target_order.iter().enumerate() {
+            rank[*q] = i;
+        }
+        let mut current: Vec<usize> = (0..self.qubits).collect();
+        for end in (1..self.qubits).rev() {
+            for i in 0..end {
+                if rank[current[i]] > rank[current[i + 1]] {
+                    current.swap(i, i + 1);
+                    self.swap(i, i + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the circuit grown to `control + 1` qubits where every gate is
+    /// controlled on `control`, so the whole circuit only acts when `control`
+    /// is set.
+    ///
+    /// `control` must be a new qubit, at least `num_qubits()`; existing qubits
+    /// fail with `CircuitError::QubitOutOfRange`. Global phases become
+    /// relative phases on `control`. Measurements and classically controlled
+    /// gates can't be controlled and fail with `CircuitError::ClassicalGate`.
+    pub fn controlled_by(&self, control: usize) -> Result<Circuit<S>, CircuitError> {
+        if control < self.qubits {
+            return Err(CircuitError::QubitOutOfRange(control));
+        }
+        let gates = self
+            .gates
+            .iter()
+            .enumerate()
+            .map(|(i, g)| {
+                if g.is_classical() {
+                    return Err(CircuitError::ClassicalGate(i));
+                }
+                g.clone()
+                    .controlled(vec![control])
+                    .ok_or(CircuitError::InvalidGate)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut circuit = self.with_gates(gates);
+        circuit.qubits = control + 1;
+        Ok(circuit)
+    }
+
+    /// Returns the qubits acted on by at least one gate, in increasing order.
+    pub fn effective_qubits(&self) -> Vec<usize> {
+        let used: BTreeSet<usize> = self
+            .gates
+            .iter()
+            .flat_map(|g| g.targets.iter().copied())
+            .collect();
+        used.into_iter().collect()
+    }
+
+    /// Returns the same circuit on a register holding only its effective
+    /// qubits, along with the mapping from new to old qubit indices.
+    ///
+    /// Qubit `i` of the compacted circuit is qubit `mapping[i]` of `self`, so
+    /// the relative order of the qubits is kept. Idle qubits stay in `|0⟩`,
+    /// so simulating the smaller circuit gives the same amplitudes.
+    pub fn compact(&self) -> (Circuit<S>, Vec<usize>) {
+        let mapping = self.effective_qubits();
+        let gates = self
+            .gates
+            .iter()
+            .map(|g| {
+                let mut g = g.clone();
+                for t in &mut g.targets {
+                    *t = mapping.binary_search(t).unwrap();
+                }
+                g
+            })
+            .collect();
+        let mut compacted = Circuit::with_scalar(mapping.len());
+        compacted.gates = gates;
+        compacted.strategy = self.strategy;
+        (compacted, mapping)
+    }
+
+    /// Estimates the cost of simulating the circuit with `Gate::apply`, as
+    /// the sum over gates of `2^k` for a gate touching `k` qubits.
+    ///
+    /// Wide (e.g. many times controlled) gates dominate this sum, while the
+    /// size of the register doesn't appear in it.
+    pub fn cost_model(&self) -> usize {
+        self.gates.iter().map(|g| g.mat.nrows()).sum()
+    }
+
+    /// Hashes the number of qubits and the name, qubits and matrix of every
+    /// gate in order, e.g. to cache work keyed by circuit structure.
+    ///
+    /// Matrix entries are rounded to 9 decimals, so numerical noise doesn't
+    /// change the hash. The hash is FNV-1a over a fixed byte encoding, so it
+    /// is stable across runs, platforms and compiler versions.
+    pub fn structural_hash(&self) -> u64 {
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                h = (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        let round = |x: f64| ((x * 1E9).round() as i64).to_le_bytes();
+        feed(&(self.qubits as u64).to_le_bytes());
+        for gate in &self.gates {
+            feed(&(gate.name.len() as u64).to_le_bytes());
+            feed(gate.name.as_bytes());
+            feed(&(gate.targets.len() as u64).to_le_bytes());
+            for q in &gate.targets {
+                feed(&(*q as u64).to_le_bytes());
+            }
+            feed(&(gate.controls as u64).to_le_bytes());
+            for j in 0..gate.mat.ncols() {
+                for i in 0..gate.mat.nrows() {
+                    feed(&round(gate.mat[(i, j)].real()));
+                    feed(&round(gate.mat[(i, j)].imag()));
+                }
+            }
+        }
+        h
+    }
+
+    /// Returns the `2^n x 2^n` matrix of the whole circuit.
+    ///
+    /// Fails with `CircuitError::TooManyQubits` past `MAX_QUBITS / 2`
+    /// qubits instead of trying to allocate the matrix, and like `statevector`
+    /// for unbound parameters or classical gates.
+    pub fn unitary(&self) -> Result<Mat<S>, CircuitError> {
+        if 2 * self.qubits > MAX_QUBITS {
+            return Err(CircuitError::TooManyQubits(self.qubits));
+        }
+        self.check_bound()?;
+        let size = 2usize.pow(self.qubits as u32);
+        Ok(self
+            .gates
+            .iter()
+            .fold(Mat::identity(size, size), |acc, gate| {
+                gate.turn_big(self.qubits) * acc
+            }))
+    }
+
+    /// Returns the Choi state `|Φ⟩⟨Φ|` of the circuit, with
+    /// `|Φ⟩ = (U ⊗ I)|Ω⟩` on `2n` qubits and `|Ω⟩` the maximally entangled
+    /// state pairing qubit `q` with qubit `q + n`. `U` acts on the upper
+    /// half of the doubled register.
+    ///
+    /// The matrix is on `2n` qubits, so this fails with
+    /// `CircuitError::TooManyQubits` past `MAX_QUBITS / 4` qubits.
+    pub fn choi_matrix(&self) -> Result<Mat<S>, CircuitError> {
+        if 4 * self.qubits > MAX_QUBITS {
+            return Err(CircuitError::TooManyQubits(self.qubits));
+        }
+        self.check_bound()?;
+        let size = 2usize.pow(self.qubits as u32);
+        let amplitude = S::from_parts(1.0 / (size as f64).sqrt(), 0.0);
+        let zero = S::from_parts(0.0, 0.0);
+        let omega = Mat::from_fn(size * size, 1, |i, _| {
+            if i / size == i % size {
+                amplitude
+            } else {
+                zero
+            }
+        });
+        let phi = kron(&self.unitary()?, &Mat::identity(size, size)) * omega;
+        Ok(&phi * phi.adjoint())
+    }
+
+    /// Checks that the matrix of the whole circuit is unitary, within the
+    /// circuit's tolerance.
+    ///
+    /// Gates built through `Gate::new` always pass, but gates whose matrix
+    /// was assembled without that check (e.g. by merging other gates) may not.
+    /// Circuits `unitary` fails on are never reported as unitary.
+    pub fn is_unitary(&self) -> bool {
+        self.unitary()
+            .is_ok_and(|u| is_unit_within(&u, self.tolerance))
+    }
+
+    /// Checks whether two circuits implement the same unitary, up to a
+    /// global phase. Circuits `unitary` fails on are never equivalent.
+    pub fn equiv(&self, other: &Circuit<S>) -> bool {
+        if other.qubits != self.qubits {
+            return false;
+        }
+        let (Ok(u), Ok(v)) = (self.unitary(), other.unitary()) else {
+            return false;
+        };
+        // U†V is a multiple of the identity iff |tr(U†V)| = 2^n
+        let prod = u.adjoint() * v;
+        let size = prod.nrows() as f64;
+        (norm(trace(&prod)) - size).abs() < self.tolerance * size
+    }
+
+    /// Returns a copy of the circuit where adjacent pairs of gates acting on
+    /// the same qubits and undoing each other are removed.
+    ///
+    /// Removing a pair can make two other gates adjacent, so sequences like
+    /// X H H X cancel out completely.
+    pub fn cancel_inverses(&self) -> Circuit<S> {
+        let mut gates: Vec<Gate<S>> = Vec::new();
+        for gate in &self.gates {
+            if let Some(last) = gates.last()
+                && last.param.is_none()
+                && gate.param.is_none()
+                && last.classical.is_none()
+                && gate.classical.is_none()
+                && last.targets == gate.targets
+                && is_identity(&(&gate.mat * &last.mat))
+            {
+                gates.pop();
+                continue;
+            }
+            gates.push(gate.clone());
+        }
+        self.with_gates(gates)
+    }
+
+    /// Returns a copy of the circuit where runs of gates acting on the same
+    /// set of qubits are multiplied into a single `fused` gate.
+    ///
+    /// This merges, for instance, consecutive rotations of a qubit, or two
+    /// controlled phases on the same pair into one controlled phase with the
+    /// summed angle (whichever qubit each one uses as control). Products of
+    /// controlled gates with the same controls keep them as controls, so the
+    /// sparse `apply` still skips the amplitudes where they aren't set.
+    ///
+    /// Gates don't need to be adjacent: a gate is moved back past the gates
+    /// it commutes with, see `gates_commute`, so H X H with X on another
+    /// qubit fuses both H.
+    pub fn fuse(&self) -> Circuit<S> {
+        let mut gates: Vec<Gate<S>> = Vec::new();
+        for gate in &self.gates {
+            let same_qubits = |g: &Gate<S>| {
+                g.targets.len() == gate.targets.len()
+                    && gate.targets.iter().all(|q| g.targets.contains(q))
+            };
+            let mut partner = None;
+            if gate.param.is_none() && gate.classical.is_none() {
+                for (j, prev) in gates.iter().enumerate().rev() {
+                    if prev.param.is_some() || prev.classical.is_some() {
+                        break;
+                    }
+                    if same_qubits(prev) {
+                        partner = Some(j);
+                        break;
+                    }
+                    if !commute_locally(prev, gate) {
+                        break;
+                    }
+                }
+            }
+            let Some(j) = partner else {
+                gates.push(gate.clone());
+                continue;
+            };
+            let last = &mut gates[j];
+            // Express `gate` with the qubit order of `last`
+            let positions: Vec<usize> = gate
+                .targets
+                .iter()
+                .map(|q| last.targets.iter().position(|p| p == q).unwrap())
+                .collect();
+            let mat = kernel::expand(gate.mat.as_ref(), &positions, last.targets.len());
+            let controls = if last.targets == gate.targets && last.controls == gate.controls {
+                last.controls
+            } else {
+                0
+            };
+            last.mat = &mat * &last.mat;
+            last.controls = controls;
+            last.name = "fused".to_string();
+            last.label = None;
+        }
+        self.with_gates(gates)
+    }
+
+    /// Lists the gates removed from `self` and added in `other`, e.g. to
+    /// check what `cancel_inverses` did.
+    ///
+    /// The gates common to both circuits are matched in order (as a longest
+    /// common subsequence), and every other gate gives a line such as
+    /// `- 3: h [0]` for gate 3 of `self` or `+ 1: cx [1, 0]` for gate 1 of
+    /// `other`, in circuit order.
+    pub fn diff(&self, other: &Circuit<S>) -> Vec<String> {
+        let (a, b) = (&self.gates, &other.gates);
+        let same = |x: &Gate<S>, y: &Gate<S>| {
+            x.name == y.name && x.targets == y.targets && mat_approx_eq(&x.mat, &y.mat)
+        };
+        // common[i][j] is the length of the longest common subsequence of
+        // a[i..] and b[j..]
+        let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                common[i][j] = if same(&a[i], &b[j]) {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+        let describe = |sign: char, k: usize, g: &Gate<S>| {
+            format!("{} {}: {} {:?}", sign, k, g.name, g.targets)
+        };
+        let mut lines = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && same(&a[i], &b[j]) {
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+                lines.push(describe('-', i, &a[i]));
+                i += 1;
+            } else {
+                lines.push(describe('+', j, &b[j]));
+                j += 1;
+            }
+        }
+        lines
+    }
+
+    /// Evolves the basis state `|index⟩` through every gate.
+    ///
+    /// While the state is a single basis state, gates sending it to another
+    /// basis state, like X, CX or S, only update its index and amplitude.
+    /// The statevector is built at the first gate creating a superposition,
+    /// such as H, and the remaining gates are applied to it.
+    ///
+    /// The tracked gates never build their `turn_big` expansion nor touch the
+    /// `2^n` amplitudes, but the returned state is still allocated in full,
+    /// so memory stays `O(2^n)` rather than constant.
+    fn evolve_basis(&self, index: usize) -> Option<Col<S>> {
+        let zero = S::from_parts(0.0, 0.0);
+        let (mut index, mut amplitude) = (index, S::from_parts(1.0, 0.0));
+        let mut done = 0;
+        for gate in &self.gates {
+            let k = gate
+                .targets
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (bit, q)| acc | ((index >> q) & 1) << bit);
+            let mut nonzero = (0..gate.mat.nrows()).filter(|i| gate.mat[(*i, k)] != zero);
+            let (Some(i), None) = (nonzero.next(), nonzero.next()) else {
+                break;
+            };
+            amplitude *= gate.mat[(i, k)];
+            index = gate
+                .targets
+                .iter()
+                .enumerate()
+                .fold(index, |acc, (bit, q)| {
+                    acc & !(1 << q) | ((i >> bit) & 1) << q
+                });
+            done += 1;
+        }
+        let mut start = self.get_vec(index)?;
+        start[index] = amplitude;
+        Some(self.evolve_range(start, done, self.gates.len()))
+    }
+
+    /// Applies the gates from `from` to `up_to`, each one with `Gate::apply`
+    /// or with its `turn_big` expansion depending on the strategy.
+    ///
+    /// Expansions of gates appearing again later are kept until their last
+    /// use, so repeated gates are only expanded once.
+    fn evolve_range(&self, mut current: Col<S>, from: usize, up_to: usize) -> Col<S> {
+        let gates = &self.gates[from..up_to];
+        let fingerprints: Vec<Option<u64>> = gates
+            .iter()
+            .map(|g| {
+                let dense = self.strategy.resolve(g.qubits().len(), self.qubits);
+                (dense == Strategy::Dense).then(|| g.fingerprint())
+            })
+            .collect();
+        let mut remaining: HashMap<u64, usize> = HashMap::new();
+        for f in fingerprints.iter().flatten() {
+            *remaining.entry(*f).or_default() += 1;
+        }
+        // Fingerprints can collide, so each entry keeps its gate to compare
+        let mut cache: HashMap<u64, Vec<(&Gate<S>, _)>> = HashMap::new();
+        for (gate, f) in gates.iter().zip(fingerprints) {
+            let Some(f) = f else {
+                gate.apply(&mut current);
+                continue;
+            };
+            let bucket = cache.entry(f).or_default();
+            let found = bucket
+                .iter()
+                .position(|(g, _)| g.targets == gate.targets && g.mat == gate.mat);
+            let count = remaining.get_mut(&f).unwrap();
+            *count -= 1;
+            let temp = match found {
+                Some(i) => &bucket[i].1 * current,
+                None if *count > 0 => {
+                    bucket.push((gate, gate.turn_big(self.qubits)));
+                    &bucket[bucket.len() - 1].1 * current
+                }
+                None => gate.turn_big(self.qubits) * current,
+            };
+            current = temp;
+            if *count == 0 {
+                cache.remove(&f);
+            }
+        }
+        current
+    }
+
+    /// Evolves `|0...0⟩` through every gate and returns the final state.
+    pub fn statevector(&self) -> Result<Col<S>, CircuitError> {
+        self.check_bound()?;
+        self.evolve_basis(0).ok_or(CircuitError::InvalidGate)
+    }
+
+    /// Evolves `|0...0⟩` through the first `up_to` gates only and returns the
+    /// intermediate state.
+    pub fn run_prefix(&self, up_to: usize) -> Result<Col<S>, CircuitError> {
+        if up_to > self.gates.len() {
+            return Err(CircuitError::GateCountOutOfRange(up_to));
+        }
+        self.check_bound()?;
+        let start = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        Ok(self.evolve_range(start, 0, up_to))
+    }
+
+    /// Returns `|0...0⟩` followed by the state after each gate, e.g. to
+    /// animate the evolution step by step.
+    pub fn trace_states(&self) -> Result<Vec<Col<S>>, CircuitError> {
+        self.check_bound()?;
+        let mut current = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        let mut states = Vec::with_capacity(self.gates.len() + 1);
+        states.push(current.clone());
+        for gate in &self.gates {
+            gate.apply(&mut current);
+            states.push(current.clone());
+        }
+        Ok(states)
+    }
+
+    /// Same as `statevector`, but applies each gate with `Gate::apply` so
+    /// no `2^n x 2^n` matrix is ever built.
+    pub fn statevector_sparse(&self) -> Result<Col<S>, CircuitError> {
+        self.check_bound()?;
+        let mut current = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        for gate in &self.gates {
+            gate.apply(&mut current);
+        }
+        Ok(current)
+    }
+
+    /// Parses a bitstring labelled like the keys of `run` (qubit 0 is the
+    /// rightmost character) into a basis index.
+    fn parse_bits(&self, bits: &str) -> Result<usize, CircuitError> {
+        if bits.len() != self.qubits || !bits.chars().all(|c| c == '0' || c == '1') {
+            return Err(CircuitError::InvalidBits(bits.to_string()));
+        }
+        Ok(usize::from_str_radix(bits, 2).unwrap_or(0))
+    }
+
+    /// Same as `run`, but starts from the basis state `|bits⟩` instead of
+    /// `|0...0⟩`.
+    pub fn run_from_bits(&self, bits: &str) -> Result<HashMap<String, S>, CircuitError> {
+        self.check_bound()?;
+        let state = self
+            .evolve_basis(self.parse_bits(bits)?)
+            .ok_or(CircuitError::InvalidGate)?;
+        Ok(self.to_map(&state))
+    }
+
+    /// Groups gate indices into layers of gates acting on disjoint qubits.
+    ///
+    /// Each gate is placed in the earliest layer after every previous gate
+    /// sharing one of its qubits, so running the layers in order is
+    /// equivalent to running the gates in order.
+    pub fn layers(&self) -> Vec<Vec<usize>> {
+        let mut depth = vec![0; self.qubits];
+        let mut layers: Vec<Vec<usize>> = Vec::new();
+        for (i, gate) in self.gates.iter().enumerate() {
+            let layer = gate.targets.iter().map(|q| depth[*q]).max().unwrap_or(0);
+            for q in &gate.targets {
+                depth[*q] = layer + 1;
+            }
+            if layer == layers.len() {
+                layers.push(Vec::new());
+            }
+            layers[layer].push(i);
+        }
+        layers
+    }
+
+    /// Groups gate indices into the columns used to draw the circuit.
+    ///
+    /// Unlike `layers`, a gate blocks every wire between its lowest and
+    /// highest qubit, so the vertical line joining its qubits never crosses
+    /// another gate.
+    fn columns(&self) -> Vec<Vec<usize>> {
+        let mut depth = vec![0; self.qubits];
+        let mut columns: Vec<Vec<usize>> = Vec::new();
+        for (i, gate) in self.gates.iter().enumerate() {
+            let (Some(lo), Some(hi)) = (gate.targets.iter().min(), gate.targets.iter().max())
+            else {
+                continue;
+            };
+            let column = depth[*lo..=*hi].iter().copied().max().unwrap_or(0);
+            depth[*lo..=*hi].fill(column + 1);
+            if column == columns.len() {
+                columns.push(Vec::new());
+            }
+            columns[column].push(i);
+        }
+        columns
+    }
+
+    /// Returns the ASCII diagram of the circuit, one wire per qubit with
+    /// qubit 0 at the top.
+    pub fn draw(&self) -> String {
+        let prefix = format!("q{}: ", self.qubits.saturating_sub(1)).len();
+        let mut wires: Vec<String> = (0..self.qubits)
+            .map(|q| format!("{:<prefix$}", format!("q{}:", q)))
+            .collect();
+        let mut spaces = vec![" ".repeat(prefix); self.qubits.saturating_sub(1)];
+        for column in self.columns() {
+            let mut labels = vec![None; self.qubits];
+            let mut links = vec![false; self.qubits.saturating_sub(1)];
+            for i in column {
+                let gate = &self.gates[i];
+                for q in gate.controls() {
+                    labels[*q] = Some("●".to_string());
+                }
+                for q in gate.target_qubits() {
+                    labels[*q] = Some(match gate.label() {
+                        Some(label) => label.to_string(),
+                        None if gate.base_name() == "x" && gate.controls > 0 => "⊕".to_string(),
+                        None => gate.base_name().to_uppercase(),
+                    });
+                }
+                let lo = *gate.targets.iter().min().unwrap();
+                let hi = *gate.targets.iter().max().unwrap();
+                for q in lo..hi {
+                    links[q] = true;
+                    if q > lo {
+                        labels[q].get_or_insert("┼".to_string());
+                    }
+                }
+            }
+            let width = labels
+                .iter()
+                .flatten()
+                .map(|l| l.chars().count())
+                .max()
+                .unwrap_or(1);
+            for (q, label) in labels.iter().enumerate() {
+                let label = label.as_deref().unwrap_or("─");
+                let pad = width - label.chars().count();
+                wires[q] += &format!("─{}{}─", label, "─".repeat(pad));
+            }
+            for (q, link) in links.iter().enumerate() {
+                let mark = if *link { "│" } else { " " };
+                spaces[q] += &format!(" {}{} ", mark, " ".repeat(width - 1));
+            }
+        }
+        let mut lines = Vec::new();
+        for (q, wire) in wires.into_iter().enumerate() {
+            lines.push(wire + "─");
+            if q < spaces.len() {
+                lines.push(spaces[q].trim_end().to_string());
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Returns the circuit as the body of a `quantikz` environment, using the
+    /// same layout as `draw`.
+    pub fn to_latex(&self) -> String {
+        let mut rows: Vec<Vec<String>> = (0..self.qubits)
+            .map(|q| vec![format!("\\lstick{{$q_{{{}}}$}}", q)])
+            .collect();
+        for column in self.columns() {
+            let mut cells = vec!["\\qw".to_string(); self.qubits];
+            for i in column {
+                let gate = &self.gates[i];
+                let anchor = gate.target_qubits().first().copied().unwrap_or(0);
+                for q in gate.controls() {
+                    cells[*q] = format!("\\ctrl{{{}}}", anchor as isize - *q as isize);
+                }
+                for q in gate.target_qubits() {
+                    cells[*q] = if gate.base_name() == "x" && gate.controls > 0 {
+                        "\\targ{}".to_string()
+                    } else {
+                        format!("\\gate{{{}}}", gate.base_name().to_uppercase())
+                    };
+                }
+            }
+            for (row, cell) in rows.iter_mut().zip(cells) {
+                row.push(cell);
+            }
+        }
+        let body = rows
+            .into_iter()
+            .map(|row| row.join(" & ") + " & \\qw")
+            .collect::<Vec<_>>()
+            .join(" \\\\\n");
+        format!("\\begin{{quantikz}}\n{}\n\\end{{quantikz}}", body)
+    }
+
+    /// Same as `statevector`, but fuses the gates of each layer into a single
+    /// operator so the state is only updated once per layer.
+    pub fn run_layered(&self) -> Result<Col<S>, CircuitError> {
+        self.check_bound()?;
+        let mut current = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        for layer in self.layers() {
+            let fused = layer[1..]
+                .iter()
+                .fold(self.gates[layer[0]].clone(), |acc, i| {
+                    acc.tensor(&self.gates[*i])
+                });
+            current = fused.turn_big(self.qubits) * current;
+        }
+        Ok(current)
+    }
+
+    fn to_map(&self, state: &Col<S>) -> HashMap<String, S> {
+        let mut res = HashMap::new();
+        for (i, x) in state.iter().enumerate() {
+            let now = format!("{:0width$b}", i, width = self.qubits);
+            res.insert(now, *x);
+        }
+        res
+    }
+
+    /// Fails with `CircuitError::UnboundParameter` if a gate angle is still
+    /// a named parameter, see `run_with`, and with
+    /// `CircuitError::ClassicalGate` if a gate measures or is classically
+    /// controlled, see `run_shot`.
+    fn check_bound(&self) -> Result<(), CircuitError> {
+        if let Some(i) = self.gates.iter().position(|g| g.classical.is_some()) {
+            return Err(CircuitError::ClassicalGate(i));
+        }
+        match self.gates.iter().find_map(|g| g.param.as_ref()) {
+            Some(p) => Err(CircuitError::UnboundParameter(p.name.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a copy of the circuit where every named parameter is replaced
+    /// by its value in `bindings`.
+    pub fn bind(&self, bindings: &HashMap<String, f64>) -> Result<Circuit<S>, CircuitError> {
+        let gates = self
+            .gates
+            .iter()
+            .map(|gate| {
+                let Some(p) = &gate.param else {
+                    return Ok(gate.clone());
+                };
+                let theta = bindings
+                    .get(&p.name)
+                    .ok_or_else(|| CircuitError::UnboundParameter(p.name.clone()))?;
+                let mut base: Gate<S> = (p.build)(p.scale * theta, gate.target_qubits()[0]).cast();
+                base.label = gate.label.clone();
+                base.classical = gate.classical;
+                match gate.controls() {
+                    [] => Ok(base),
+                    controls => base
+                        .controlled(controls.to_vec())
+                        .ok_or(CircuitError::InvalidGate),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.with_gates(gates))
+    }
+
+    /// Same as `run`, with the named parameters of the gates set to their
+    /// value in `bindings`.
+    pub fn run_with(
+        &self,
+        bindings: &HashMap<String, f64>,
+    ) -> Result<HashMap<String, S>, CircuitError> {
+        self.bind(bindings)?.run()
+    }
+
+    /// Evolves `|0...0⟩` and hands the final state to `sink` in chunks of
+    /// `2^chunk_bits` amplitudes, as `sink(k, amplitudes)` for the `k`-th
+    /// chunk, i.e. the basis states `k * 2^chunk_bits..(k + 1) * 2^chunk_bits`.
+    ///
+    /// Gates that only mix amplitudes within a chunk (diagonal gates, and
+    /// gates whose non-control targets are below `chunk_bits`) never move
+    /// amplitudes out of the first chunk, so while only such gates have been
+    /// applied every other chunk is zero. If the whole circuit is made of
+    /// them, only the first chunk and a chunk of zeros are ever allocated.
+    ///
+    /// From the first other gate on, the whole statevector is kept in memory,
+    /// with consecutive chunk-local gates applied chunk by chunk while each
+    /// chunk is hot in cache. The chunks are then handed out of it directly,
+    /// without any copy, but peak memory is then the same as `statevector`:
+    /// this is only out-of-core for circuits made of chunk-local gates.
+    pub fn run_chunked(
+        &self,
+        chunk_bits: usize,
+        mut sink: impl FnMut(usize, &[S]),
+    ) -> Result<(), CircuitError> {
+        self.check_bound()?;
+        let size = 1usize << self.qubits;
+        let len = 1 << chunk_bits.min(self.qubits);
+        let local: Vec<bool> = self
+            .gates
+            .iter()
+            .map(|g| kernel::is_chunk_local(g.mat.as_ref(), &g.targets, g.controls, chunk_bits))
+            .collect();
+        let zero = S::from_parts(0.0, 0.0);
+        let mut first = vec![zero; len];
+        first[0] = S::from_parts(1.0, 0.0);
+        let mixing = local.iter().position(|l| !l).unwrap_or(self.gates.len());
+        for g in &self.gates[..mixing] {
+            let chunk = ColMut::from_slice_mut(&mut first);
+            kernel::apply_chunk(g.mat.as_ref(), &g.targets, g.controls, chunk, 0);
+        }
+        if mixing == self.gates.len() {
+            sink(0, &first);
+            first.fill(zero);
+            for k in 1..size / len {
+                sink(k, &first);
+            }
+            return Ok(());
+        }
+
+        let mut state = Col::from_fn(size, |i| if i < len { first[i] } else { zero });
+        drop(first);
+        let mut start = mixing;
+        while start < self.gates.len() {
+            let end = start + local[start..].iter().take_while(|l| **l).count();
+            if end == start {
+                self.gates[start].apply(&mut state);
+                start += 1;
+                continue;
+            }
+            for offset in (0..size).step_by(len) {
+                for g in &self.gates[start..end] {
+                    let chunk = state.as_mut().subrows_mut(offset, len);
+                    kernel::apply_chunk(g.mat.as_ref(), &g.targets, g.controls, chunk, offset);
+                }
+            }
+            start = end;
+        }
+        let amplitudes = state.try_as_col_major().unwrap().as_slice();
+        for (k, chunk) in amplitudes.chunks(len).enumerate() {
+            sink(k, chunk);
+        }
+        Ok(())
+    }
+
+    /// Evolves `|0...0⟩` and maps each bitstring to its final amplitude.
+    ///
+    /// Fails with `CircuitError::StateCollapsed` if the final state has zero
+    /// norm, which can only come from a gate that isn't unitary.
+    pub fn run(&self) -> Result<HashMap<String, S>, CircuitError> {
+        let state = self.statevector()?;
+        let total: f64 = state.iter().map(|x| norm(*x) * norm(*x)).sum();
+        if total < self.tolerance {
+            return Err(CircuitError::StateCollapsed);
+        }
+        Ok(self.to_map(&state))
+    }
+
+    /// Same as `run`, but fails with `CircuitError::NonUnitary` if the norm
+    /// of the final state is further than the circuit's tolerance from 1,
+    /// see `set_tolerance`.
+    pub fn run_checked(&self) -> Result<HashMap<String, S>, CircuitError> {
+        let state = self.statevector()?;
+        let total: f64 = state.iter().map(|x| norm(*x) * norm(*x)).sum();
+        if (total.sqrt() - 1.0).abs() > self.tolerance {
+            return Err(CircuitError::NonUnitary);
+        }
+        Ok(self.to_map(&state))
+    }
+
+    /// Same as `run`, but returns the amplitudes in a vector indexed by the
+    /// basis state, skipping the bitstring labels.
+    pub fn run_indexed(&self) -> Result<Vec<S>, CircuitError> {
+        Ok(self.statevector()?.iter().copied().collect())
+    }
+
+    /// Returns the final amplitude of the basis state `bits`, labelled like
+    /// the keys of `run`.
+    pub fn amplitude_str(&self, bits: &str) -> Result<S, CircuitError> {
+        let index = self.parse_bits(bits)?;
+        Ok(self.statevector()?[index])
+    }
+
+    /// Same as `amplitude_str` in `O(gates)` time for circuits made only of
+    /// diagonal gates, which keep `|0...0⟩` on itself up to a phase.
+    ///
+    /// Like `run`, the input state is always `|0...0⟩`, so every other
+    /// amplitude is zero; there is no shortcut for other input basis states.
+    ///
+    /// Returns `None` if a gate isn't diagonal, has an unbound angle, or is a
+    /// measurement or classically controlled, or if `bits` isn't a valid
+    /// label.
+    pub fn amplitude_fast(&self, bits: &str) -> Option<S> {
+        let index = self.parse_bits(bits).ok()?;
+        if !self.gates.iter().all(Self::is_phase_gate) {
+            return None;
+        }
+        if index != 0 {
+            return Some(S::from_parts(0.0, 0.0));
+        }
+        Some(
+            self.gates
+                .iter()
+                .fold(S::from_parts(1.0, 0.0), |acc, g| acc * g.mat[(0, 0)]),
+        )
+    }
+
+    /// Runs the circuit and yields each `(index, amplitude)` pair of the
+    /// final state by increasing basis index, without labelling them like
+    /// `run`.
+    pub fn amplitudes_iter(&self) -> Result<impl Iterator<Item = (usize, S)>, CircuitError> {
+        let state = self.statevector()?;
+        Ok((0..state.nrows()).map(move |i| (i, state[i])))
+    }
+
+    /// Same as `run`, but divides the state by the phase of its first
+    /// amplitude with a non-negligible norm, so circuits differing only by a
+    /// global phase give the same result.
+    pub fn run_canonical(&self) -> Result<HashMap<String, S>, CircuitError> {
+        let mut state = self.statevector()?;
+        let first = state.iter().copied().find(|x| norm(*x) > 1E-10);
+        if let Some(x) = first {
+            let r = norm(x);
+            let phase = S::from_parts(x.real() / r, -x.imag() / r);
+            for y in state.iter_mut() {
+                *y *= phase;
+            }
+        }
+        Ok(self.to_map(&state))
+    }
+
+    /// Returns the probability of measuring each basis state.
+    pub fn probabilities(&self) -> Result<HashMap<String, f64>, CircuitError> {
+        let mut res = self.run()?;
+        Ok(res.drain().map(|(k, x)| (k, norm(x) * norm(x))).collect())
+    }
+
+    /// Returns every basis state with its probability, most probable first.
+    /// Ties are ordered by bitstring.
+    pub fn probability_ranking(&self) -> Result<Vec<(String, f64)>, CircuitError> {
+        let mut ranking: Vec<(String, f64)> = self.probabilities()?.into_iter().collect();
+        ranking.sort_by(|(a, p), (b, q)| q.total_cmp(p).then_with(|| a.cmp(b)));
+        Ok(ranking)
+    }
+
+    /// Returns the expectation value of a Pauli string on the final state.
+    ///
+    /// The string is labelled like the keys of `run`: its rightmost letter
+    /// acts on qubit 0.
+    pub fn expectation(&self, pauli: &str) -> Result<f64, CircuitError> {
+        self.check_pauli(pauli)?;
+        let state = self.statevector()?;
+        Ok(pauli_expectation(&state, pauli))
+    }
+
+    /// Returns the energy `Σ c_k ⟨P_k⟩` of the final state for a Hamiltonian
+    /// given as a weighted sum of Pauli strings.
+    ///
+    /// Every string is checked before the circuit is run, and the state is
+    /// only computed once.
+    pub fn energy(&self, ham: &Hamiltonian) -> Result<f64, CircuitError> {
+        for (_, pauli) in &ham.terms {
+            self.check_pauli(pauli)?;
+        }
+        let state = self.statevector()?;
+        Ok(ham
+            .terms
+            .iter()
+            .map(|(c, pauli)| c * pauli_expectation(&state, pauli))
+            .sum())
+    }
+
+    /// Estimates the expectation value of a Pauli string from `shots`
+    /// measurements in its eigenbasis, returning the estimate and its
+    /// standard error.
+    pub fn expectation_sampled(
+        &self,
+        pauli: &str,
+        shots: usize,
+        seed: u64,
+    ) -> Result<(f64, f64), CircuitError> {
+        self.check_pauli(pauli)?;
+        if shots < 2 {
+            return Err(CircuitError::InvalidShots(shots));
+        }
+        // Rotate each X or Y eigenbasis onto the Z one
+        let mut rotated = self.clone();
+        for (q, letter) in pauli.chars().rev().enumerate() {
+            if letter == 'Y' {
+                rotated.p(-std::f64::consts::FRAC_PI_2, q)?;
+            }
+            if letter == 'X' || letter == 'Y' {
+                rotated.h(q)?;
+            }
+        }
+        let mask: Vec<bool> = pauli.chars().map(|c| c != 'I').collect();
+        let sum: f64 = rotated
+            .sample_shots(shots, seed)?
+            .iter()
+            .map(|bits| {
+                let ones = bits.chars().zip(&mask).filter(|(b, m)| **m && *b == '1');
+                if ones.count() % 2 == 0 { 1.0 } else { -1.0 }
+            })
+            .sum();
+        let n = shots as f64;
+        let mean = sum / n;
+        // Unbiased variance of ±1 outcomes with this mean
+        let variance = (1.0 - mean * mean) * n / (n - 1.0);
+        Ok((mean, (variance / n).sqrt()))
+    }
+
+    fn check_pauli(&self, pauli: &str) -> Result<(), CircuitError> {
+        if pauli.len() != self.qubits || !pauli.chars().all(|c| "IXYZ".contains(c)) {
+            return Err(CircuitError::InvalidPauli(pauli.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Returns the expectation value of the product of Z over `qubits`, i.e.
+    /// the parity of those qubits, in `[-1, 1]`.
+    pub fn parity(&self, qubits: &[usize]) -> Result<f64, CircuitError> {
+        let mut pauli = vec!['I'; self.qubits];
+        for q in qubits {
+            self.check_qubit(*q)?;
+            pauli[self.qubits - 1 - q] = 'Z';
+        }
+        self.expectation(&pauli.into_iter().collect::<String>())
+    }
+
+    /// Measures every qubit of the final state `shots` times and returns
+    /// each outcome, labelled like the keys of `run`, in the order drawn.
+    ///
+    /// The same `seed` always gives the same sequence.
+    pub fn sample_shots(&self, shots: usize, seed: u64) -> Result<Vec<String>, CircuitError> {
+        let state = self.statevector()?;
+        let mut cumulative = Vec::with_capacity(state.nrows());
+        let mut total = 0.0;
+        for x in state.iter() {
+            total += norm(*x) * norm(*x);
+            cumulative.push(total);
+        }
+        if total < self.tolerance {
+            return Err(CircuitError::StateCollapsed);
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        Ok((0..shots)
+            .map(|_| {
+                let r = rng.random::<f64>() * total;
+                let i = cumulative
+                    .partition_point(|c| *c <= r)
+                    .min(cumulative.len() - 1);
+                format!("{:0width$b}", i, width = self.qubits)
+            })
+            .collect())
+    }
+
+    /// Samples one noisy evolution of `|0...0⟩`: after each gate, every
+    /// qubit it touches gets a random Pauli error with the probability given
+    /// by `noise`.
+    ///
+    /// The same `seed` always gives the same trajectory. Averaging
+    /// `|ψ⟩⟨ψ|` over many seeds approximates the noisy density matrix.
+    pub fn run_trajectory(&self, noise: &NoiseModel, seed: u64) -> Result<Col<S>, CircuitError> {
+        self.check_bound()?;
+        let mut current = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        let paulis: [fn(usize) -> Gate; 3] = [Gate::x, Gate::y, Gate::z];
+        let mut rng = StdRng::seed_from_u64(seed);
+        for gate in &self.gates {
+            gate.apply(&mut current);
+            let p = noise.probability(gate.name());
+            for q in gate.qubits() {
+                if rng.random::<f64>() < p {
+                    let error: Gate<S> = paulis[rng.random_range(0..3)](*q).cast();
+                    error.apply(&mut current);
+                }
+            }
+        }
+        Ok(current)
+    }
+
+    /// Checks that every classically controlled gate reads a classical bit
+    /// an earlier gate measured into, failing with
+    /// `CircuitError::UndefinedClassicalBit` otherwise.
+    pub fn validate(&self) -> Result<(), CircuitError> {
+        let mut written = HashSet::new();
+        for gate in &self.gates {
+            match gate.classical {
+                Some(Classical::Measure(c)) => {
+                    written.insert(c);
+                }
+                Some(Classical::If(c)) if !written.contains(&c) => {
+                    return Err(CircuitError::UndefinedClassicalBit(c));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Samples one run of a circuit with mid-circuit measurements, returning
+    /// the final state and the classical bits, indexed by bit, with the ones
+    /// never measured left at `false`.
+    ///
+    /// Each measurement collapses and renormalizes the state. The same `seed`
+    /// always gives the same outcomes.
+    pub fn run_shot(&self, seed: u64) -> Result<(Col<S>, Vec<bool>), CircuitError> {
+        if let Some(p) = self.gates.iter().find_map(|g| g.param.as_ref()) {
+            return Err(CircuitError::UnboundParameter(p.name.clone()));
+        }
+        self.validate()?;
+        let cbits = self
+            .gates
+            .iter()
+            .filter_map(|g| match g.classical {
+                Some(Classical::Measure(c)) => Some(c + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mut bits = vec![false; cbits];
+        let mut current = self.get_vec(0).ok_or(CircuitError::InvalidGate)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let zero = S::from_parts(0.0, 0.0);
+        for gate in &self.gates {
+            match gate.classical {
+                None => gate.apply(&mut current),
+                Some(Classical::If(c)) => {
+                    if bits[c] {
+                        gate.apply(&mut current);
+                    }
+                }
+                Some(Classical::Measure(c)) => {
+                    let q = gate.targets[0];
+                    let (mut p0, mut p1) = (0.0, 0.0);
+                    for (i, x) in current.iter().enumerate() {
+                        match (i >> q) & 1 {
+                            0 => p0 += norm(*x) * norm(*x),
+                            _ => p1 += norm(*x) * norm(*x),
+                        }
+                    }
+                    if p0 + p1 < self.tolerance {
+                        return Err(CircuitError::StateCollapsed);
+                    }
+                    let outcome = rng.random::<f64>() * (p0 + p1) < p1;
+                    let scale = S::from_parts(1.0 / if outcome { p1 } else { p0 }.sqrt(), 0.0);
+                    for i in 0..current.nrows() {
+                        current[i] = if ((i >> q) & 1 == 1) == outcome {
+                            current[i] * scale
+                        } else {
+                            zero
+                        };
+                    }
+                    bits[c] = outcome;
+                }
+            }
+        }
+        Ok((current, bits))
+    }
+
+    /// Measures `qubits` of the final state, returning each one's outcome
+    /// and the renormalized state of the other qubits after the collapse.
+    ///
+    /// The remaining qubits keep their relative order: qubit `i` of the
+    /// returned state is the `i`-th smallest unmeasured qubit. The same
+    /// `seed` always gives the same outcomes.
+    pub fn measure_some(
+        &self,
+        qubits: &[usize],
+        seed: u64,
+    ) -> Result<(HashMap<usize, usize>, Col<S>), CircuitError> {
+        for q in qubits {
+            self.check_qubit(*q)?;
+        }
+        let state = self.statevector()?;
+        let mask = qubits.iter().fold(0, |acc, q| acc | (1 << q));
+        let measured: Vec<usize> = (0..self.qubits).filter(|q| mask & (1 << q) != 0).collect();
+        // Probability of each joint outcome, indexed by the measured bits
+        // packed together, with the smallest measured qubit as bit 0
+        let mut probabilities = vec![0.0; 1 << measured.len()];
+        for (i, x) in state.iter().enumerate() {
+            let j = measured
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (k, q)| acc | (((i >> q) & 1) << k));
+            probabilities[j] += norm(*x) * norm(*x);
+        }
+        let total: f64 = probabilities.iter().sum();
+        if total < self.tolerance {
+            return Err(CircuitError::StateCollapsed);
+        }
+        let mut r = StdRng::seed_from_u64(seed).random::<f64>() * total;
+        let j = probabilities
+            .iter()
+            .position(|p| {
+                r -= p;
+                r < 0.0
+            })
+            .unwrap_or_else(|| probabilities.iter().rposition(|p| *p > 0.0).unwrap());
+        let p = probabilities[j];
+        let bits = measured
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (k, q)| acc | (((j >> k) & 1) << q));
+
+        let rest: Vec<usize> = (0..self.qubits).filter(|q| mask & (1 << q) == 0).collect();
+        let scale = S::from_parts(1.0 / p.sqrt(), 0.0);
+        let collapsed = Col::from_fn(1 << rest.len(), |j| {
+            let i = rest
+                .iter()
+                .enumerate()
+                .fold(bits, |acc, (k, q)| acc | (((j >> k) & 1) << q));
+            state[i] * scale
+        });
+        let outcomes = qubits.iter().map(|q| (*q, (bits >> q) & 1)).collect();
+        Ok((outcomes, collapsed))
+    }
+
+    /// Returns the phase angle, in `(-π, π]`, of every basis state's
+    /// amplitude.
+    ///
+    /// Basis states with a (near) zero amplitude have no meaningful phase and
+    /// are left out.
+    pub fn phases(&self) -> Result<HashMap<String, f64>, CircuitError> {
+        let mut res = HashMap::new();
+        for (k, x) in self.run()? {
+            if norm(x) < 1E-10 {
+                continue;
+            }
+            res.insert(k, phase_angle(x.real(), x.imag()));
+        }
+        Ok(res)
+    }
+
+    /// Returns the global phase, in `(-π, π]`, picked up by the circuit.
+    ///
+    /// If `|0...0⟩` is sent to a single basis state, this is the phase of its
+    /// amplitude. Otherwise the global phase isn't observable from the state
+    /// alone, and the sum of the angles of the `global_phase` gates is
+    /// returned instead.
+    pub fn global_phase_of(&self) -> Result<f64, CircuitError> {
+        let state = self.statevector()?;
+        let significant: Vec<S> = state.iter().copied().filter(|x| norm(*x) > 1E-10).collect();
+        if let [x] = significant[..] {
+            return Ok(phase_angle(x.real(), x.imag()));
+        }
+        let (re, im) = self.gates.iter().filter(|g| g.name == "global_phase").fold(
+            (1.0, 0.0),
+            |(re, im), g| {
+                let x = g.mat[(0, 0)];
+                (re * x.real() - im * x.imag(), re * x.imag() + im * x.real())
+            },
+        );
+        Ok(phase_angle(re, im))
+    }
+
+    /// Same as `run`, but only keeps the basis states whose amplitude has a
+    /// norm above `threshold`.
+    pub fn run_sparse(&self, threshold: f64) -> Result<HashMap<String, S>, CircuitError> {
+        let mut res = HashMap::new();
+        for (i, x) in self.statevector()?.iter().enumerate() {
+            if norm(*x) > threshold {
+                res.insert(format!("{:0width$b}", i, width = self.qubits), *x);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Measures `qubit` on the final state and keeps the branch where
+    /// `outcome` was observed.
+    ///
+    /// Returns the probability of that outcome together with the projected
+    /// statevector, renormalized to unit length.
+    pub fn measure_and_project(
+        &self,
+        qubit: usize,
+        outcome: usize,
+    ) -> Result<(f64, Col<S>), CircuitError> {
+        self.check_qubit(qubit)?;
+        if outcome > 1 {
+            return Err(CircuitError::InvalidOutcome(outcome));
+        }
+        let mut state = self.statevector()?;
+        let mut prob = 0.0;
+        for (i, x) in state.iter_mut().enumerate() {
+            if (i >> qubit) & 1 == outcome {
+                prob += norm(*x) * norm(*x);
+            } else {
+                *x = S::from_parts(0.0, 0.0);
+            }
+        }
+        if prob < self.tolerance {
+            return Err(CircuitError::InvalidOutcome(outcome));
+        }
+        let scale = S::from_parts(1.0 / prob.sqrt(), 0.0);
+        for x in state.iter_mut() {
+            *x *= scale;
+        }
+        Ok((prob, state))
+    }
+}
+
+/// Bracket written around basis state labels by `display_result_opts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Bracket {
+    /// `|01⟩`
+    #[default]
+    Ket,
+    /// `⟨01|`
+    Bra,
+}
+
+/// How `display_result_opts` writes the parts of each amplitude.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NumberFormat {
+    /// Five decimals, e.g. `0.70711`.
+    #[default]
+    Fixed,
+    /// Same as `Fixed`, but amplitudes whose norm is nonzero and below `min`
+    /// or above `max` are written in scientific notation, e.g. `1.00000e-8`.
+    Auto { min: f64, max: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DisplayOptions {
+    pub bracket: Bracket,
+    /// Writes qubit 0 as the leftmost bit instead of the rightmost one.
+    pub reverse_bits: bool,
+    pub numbers: NumberFormat,
+}
+
+/// Formats every amplitude of `res` on its own line, sorted by label.
+pub fn format_result_opts<S: Scalar>(res: &HashMap<String, S>, opts: DisplayOptions) -> String {
+    let mut lines = res
+        .iter()
+        .map(|(bits, x)| {
+            let label = if opts.reverse_bits {
+                bits.chars().rev().collect()
+            } else {
+                bits.clone()
+            };
+            (label, *x)
+        })
+        .collect::<Vec<_>>();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+    lines
+        .into_iter()
+        .map(|(label, x)| {
+            let label = match opts.bracket {
+                Bracket::Ket => format!("|{}⟩", label),
+                Bracket::Bra => format!("⟨{}|", label),
+            };
+            let scientific = match opts.numbers {
+                NumberFormat::Fixed => false,
+                NumberFormat::Auto { min, max } => {
+                    let n = norm(x);
+                    n != 0.0 && (n < min || n > max)
+                }
+            };
+            let (re, im) = if scientific {
+                (
+                    format!("{:.5e}", x.real().abs()),
+                    format!("{:.5e}", x.imag().abs()),
+                )
+            } else {
+                (
+                    format!("{:.5}", x.real().abs()),
+                    format!("{:.5}", x.imag().abs()),
+                )
+            };
+            format!(
+                "{}: {}{} {} i{}",
+                label,
+                if x.real() >= 0.0 { " " } else { "-" },
+                re,
+                if x.imag() >= 0.0 { "+" } else { "-" },
+                im
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Prints `res` using the given label conventions.
+pub fn display_result_opts<S: Scalar>(res: &HashMap<String, S>, opts: DisplayOptions) {
+    if !res.is_empty() {
+        println!("{}", format_result_opts(res, opts));
+    }
+}
+
+pub fn display_result<S: Scalar>(res: &HashMap<String, S>) {
+    display_result_opts(res, DisplayOptions::default());
+}
+
+/// Formats measurement counts as a bar chart, one line per label sorted by
+/// label, with bars of `#` scaled so the largest count is `width` long.
+///
+/// Labels are right-aligned so the bars line up.
+pub fn format_histogram(counts: &HashMap<String, usize>, width: usize) -> String {
+    let max = counts.values().copied().max().unwrap_or(0).max(1);
+    let label_width = counts.keys().map(|l| l.chars().count()).max().unwrap_or(0);
+    let mut lines: Vec<_> = counts.iter().collect();
+    lines.sort();
+    lines
+        .into_iter()
+        .map(|(label, count)| {
+            let bar = "#".repeat((count * width + max / 2) / max);
+            format!("{:>lw$} | {} {}", label, bar, count, lw = label_width)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Prints `counts` as a bar chart, see `format_histogram`.
+pub fn display_histogram(counts: &HashMap<String, usize>, width: usize) {
+    if !counts.is_empty() {
+        println!("{}", format_histogram(counts, width));
     }
 }
@@ -1,9 +1,19 @@
-use crate::core::{C, Circuit, Gate, ONE, Z, is_identity, is_unit, norm};
+use std::collections::HashMap;
 
-use faer::mat;
+use crate::core::{
+    Bracket, C, Circuit, CircuitError, DisplayOptions, EXPANSIONS, Gate, IM, MAX_QUBITS,
+    NumberFormat, ONE, Parameter, Strategy, TOLERANCE, Z, approx_eq, assert_state_approx,
+    bit_reverse, cplx, format_histogram, format_result_opts, gates_commute, inner_product,
+    is_identity, is_unit, mat_approx_eq, norm, reverse_statevector, states_equal_up_to_permutation,
+    trace,
+};
+use crate::noise::NoiseModel;
+use crate::observables::Hamiltonian;
+
+use faer::{Col, Mat, c32, c64, mat};
 
 fn approx(a: C, b: C) -> bool {
-    norm(a - b) < 1e-5
+    approx_eq(a, b, TOLERANCE)
 }
 
 #[test]
@@ -85,7 +95,7 @@ fn test_run_h_single_qubit() {
     c.h(0).unwrap();
     let res = c.run().unwrap();
 
-    let x = C::from_f64(1.0 / (2.0_f64).sqrt());
+    let x = cplx(1.0 / (2.0_f64).sqrt(), 0.0);
     assert!(approx(res["0"], x));
     assert!(approx(res["1"], x));
 }
@@ -98,7 +108,7 @@ fn test_bell_state() {
     c.cx(0, 1).unwrap();
 
     let res = c.run().unwrap();
-    let x = C::from_f64(1.0 / (2.0_f64).sqrt());
+    let x = cplx(1.0 / (2.0_f64).sqrt(), 0.0);
 
     assert!(approx(res["00"], x));
     assert!(approx(res["11"], x));
@@ -120,3 +130,2456 @@ fn test_circuit_vector_output_format() {
         }
     }
 }
+
+#[test]
+fn test_controlled_targets_order() {
+    // Controls are appended after the original targets
+    let g = Gate::h(2).controlled(vec![0, 1]).unwrap();
+    assert_eq!(g.targets, vec![2, 0, 1]);
+}
+
+#[test]
+fn test_controlled_rejects_overlap() {
+    assert!(Gate::x(1).controlled(vec![1]).is_none());
+    assert!(Gate::x(1).controlled(vec![0, 0]).is_none());
+    assert!(Gate::cx(2, 2).is_none());
+}
+
+#[test]
+fn test_cx_control_above_target_truth_table() {
+    // cx(3, 1) flips qubit 1 exactly when qubit 3 is set
+    let big = Gate::cx(3, 1).unwrap().turn_big(4);
+    for col in 0..16 {
+        let expected = if (col >> 3) & 1 == 1 { col ^ 0b10 } else { col };
+        for row in 0..16 {
+            let want = if row == expected { ONE } else { Z };
+            assert!(approx(big[(row, col)], want));
+        }
+    }
+}
+
+#[test]
+fn test_gates_commute() {
+    // Disjoint qubits
+    assert!(gates_commute(&Gate::h(0), &Gate::x(1), 2));
+
+    // Sharing a qubit
+    assert!(!gates_commute(&Gate::h(0), &Gate::x(0), 2));
+    assert!(!gates_commute(&Gate::h(1), &Gate::cx(0, 1).unwrap(), 2));
+
+    // Overlapping but still commuting
+    assert!(gates_commute(&Gate::z(0), &Gate::cx(0, 1).unwrap(), 2));
+
+    // Every gate commutes with itself
+    let cx = Gate::cx(1, 0).unwrap();
+    assert!(gates_commute(&cx, &cx, 2));
+    assert!(gates_commute(&Gate::h(0), &Gate::h(0), 1));
+}
+
+#[test]
+fn test_cancel_inverses() {
+    let mut c = Circuit::new(2);
+    c.h(1).unwrap();
+    c.x(0).unwrap();
+    c.x(0).unwrap();
+    let optimized = c.cancel_inverses();
+    assert_eq!(optimized.gates.len(), 1);
+
+    let before = c.run().unwrap();
+    let after = optimized.run().unwrap();
+    for (bits, amp) in &before {
+        assert!(approx(*amp, after[bits]));
+    }
+
+    // Cancelling a pair can expose another one
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    c.h(0).unwrap();
+    c.h(0).unwrap();
+    c.x(0).unwrap();
+    assert!(c.cancel_inverses().gates.is_empty());
+
+    // Non-inverse neighbours are kept
+    let mut c = Circuit::new(1);
+    c.s(0).unwrap();
+    c.s(0).unwrap();
+    assert_eq!(c.cancel_inverses().gates.len(), 2);
+}
+
+#[test]
+fn test_gate_eigen() {
+    let (values, vectors) = Gate::z(0).eigen();
+    assert_eq!(values.len(), 2);
+    for (i, value) in values.iter().enumerate() {
+        // +1 goes with |0⟩ and -1 with |1⟩, up to a phase on the eigenvector
+        let basis = if approx(*value, ONE) {
+            0
+        } else {
+            assert!(approx(*value, -ONE));
+            1
+        };
+        assert!((norm(vectors[(basis, i)]) - 1.0).abs() < 1e-5);
+        assert!(norm(vectors[(1 - basis, i)]) < 1e-5);
+    }
+    assert!(!approx(values[0], values[1]));
+
+    for g in [Gate::h(0), Gate::t(0), Gate::cx(0, 1).unwrap()] {
+        for value in g.eigen().0 {
+            assert!((norm(value) - 1.0).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn test_measure_and_project_bell() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+
+    let (prob, state) = c.measure_and_project(0, 1).unwrap();
+    assert!((prob - 0.5).abs() < 1e-5);
+    assert!(approx(state[0b11], ONE));
+    for i in [0b00, 0b01, 0b10] {
+        assert!(approx(state[i], Z));
+    }
+
+    assert_eq!(
+        c.measure_and_project(0, 2).unwrap_err(),
+        CircuitError::InvalidOutcome(2)
+    );
+    assert_eq!(
+        c.measure_and_project(2, 0).unwrap_err(),
+        CircuitError::QubitOutOfRange(2)
+    );
+
+    // Outcome that can never be observed
+    let c = Circuit::new(1);
+    assert_eq!(
+        c.measure_and_project(0, 1).unwrap_err(),
+        CircuitError::InvalidOutcome(1)
+    );
+}
+
+#[test]
+fn test_run_sparse_bell() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+
+    let res = c.run_sparse(1e-8).unwrap();
+    let mut keys = res.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["00", "11"]);
+
+    // The full output is left untouched
+    assert_eq!(c.run().unwrap().len(), 4);
+}
+
+#[test]
+fn test_layers() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.x(1).unwrap();
+    assert_eq!(c.layers(), vec![vec![0, 1]]);
+
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.x(2).unwrap();
+    c.h(1).unwrap();
+    assert_eq!(c.layers(), vec![vec![0, 2], vec![1], vec![3]]);
+}
+
+#[test]
+fn test_run_layered_matches_run() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.x(2).unwrap();
+    c.cx(0, 1).unwrap();
+    c.t(2).unwrap();
+    c.h(2).unwrap();
+    c.s(0).unwrap();
+    c.cx(2, 0).unwrap();
+
+    let expected = c.statevector().unwrap();
+    let layered = c.run_layered().unwrap();
+    for i in 0..8 {
+        assert!(approx(expected[i], layered[i]));
+    }
+}
+
+#[test]
+fn test_phases() {
+    // (|0⟩ - |1⟩)/√2
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    c.h(0).unwrap();
+    let phases = c.phases().unwrap();
+    assert!(phases["0"].abs() < 1e-5);
+    assert!((phases["1"] - std::f64::consts::PI).abs() < 1e-5);
+
+    // Zero amplitudes are left out
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.s(0).unwrap();
+    let phases = c.phases().unwrap();
+    assert_eq!(phases.len(), 2);
+    assert!((phases["01"] - std::f64::consts::FRAC_PI_2).abs() < 1e-5);
+
+    let probs = c.probabilities().unwrap();
+    assert!((probs["00"] - 0.5).abs() < 1e-5);
+    assert!(probs["10"].abs() < 1e-5);
+}
+
+#[test]
+fn test_gate_names() {
+    assert_eq!(Gate::h(0).name(), "h");
+    assert_eq!(Gate::cx(0, 1).unwrap().name(), "cx");
+    assert_eq!(Gate::cnx(vec![0, 1], 2).unwrap().name(), "ccx");
+    assert_eq!(
+        Gate::new(mat![[ONE, Z], [Z, ONE]], vec![0]).unwrap().name(),
+        "unitary"
+    );
+}
+
+#[test]
+fn test_clone_and_debug() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let copy = c.clone();
+
+    let a = c.run().unwrap();
+    let b = copy.run().unwrap();
+    for (bits, amp) in &a {
+        assert!(approx(*amp, b[bits]));
+    }
+
+    let debug = format!("{:?}", copy);
+    assert!(debug.contains("gate_count: 2"));
+    assert!(debug.contains("\"cx\""));
+    assert!(debug.contains("qubits: 2"));
+}
+
+#[test]
+fn test_registers() {
+    let mut c = Circuit::with_registers(&[("a".to_string(), 2), ("b".to_string(), 1)]);
+    assert_eq!(c.num_qubits(), 3);
+    assert_eq!(c.qubit("a", 1), Ok(1));
+
+    let b0 = c.qubit("b", 0).unwrap();
+    c.x(b0).unwrap();
+    assert_eq!(c.gates[0].targets, vec![2]);
+    assert!(approx(c.run().unwrap()["100"], ONE));
+
+    assert_eq!(c.qubit("b", 1), Err(CircuitError::QubitOutOfRange(1)));
+    assert_eq!(
+        c.qubit("q", 0),
+        Err(CircuitError::UnknownRegister("q".to_string()))
+    );
+}
+
+#[test]
+fn test_gate_controls() {
+    let g = Gate::h(2).controlled(vec![0]).unwrap();
+    let g = g.controlled(vec![1]).unwrap();
+    assert_eq!(g.controls(), &[0, 1]);
+    assert_eq!(g.target_qubits(), &[2]);
+    assert_eq!(g.name(), "cch");
+    assert_eq!(g.base_name(), "h");
+    assert!(Gate::h(0).controls().is_empty());
+}
+
+#[test]
+fn test_draw() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 2).unwrap();
+    c.x(1).unwrap();
+    let expected = [
+        "q0: ─H──●─────",
+        "        │",
+        "q1: ────┼──X──",
+        "        │",
+        "q2: ────⊕─────",
+    ];
+    assert_eq!(c.draw(), expected.join("\n"));
+}
+
+#[test]
+fn test_to_latex_bell() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let latex = c.to_latex();
+    assert!(latex.contains("\\gate{H}"));
+    assert!(latex.contains("\\ctrl{1}"));
+    assert!(latex.contains("\\targ{}"));
+    assert!(latex.starts_with("\\begin{quantikz}"));
+    assert_eq!(latex.lines().count(), 4);
+}
+
+#[test]
+fn test_apply_matches_turn_big() {
+    let n = 6;
+    let state: Col<C> = Col::from_fn(1 << n, |i| cplx(i as f64 * 0.1, (i % 7) as f64));
+
+    let gates = [
+        Gate::cnx(vec![4, 1], 3).unwrap(),
+        Gate::h(2),
+        Gate::t(5).controlled(vec![0]).unwrap(),
+        Gate::cx(3, 0).unwrap(),
+    ];
+    for gate in gates {
+        let expected = gate.turn_big(n) * &state;
+        let mut actual = state.clone();
+        gate.apply(&mut actual);
+        for i in 0..1 << n {
+            assert!(approx(actual[i], expected[i]));
+        }
+    }
+}
+
+#[test]
+fn test_gate_from_npy() {
+    let path = std::env::temp_dir().join("rustomic_gate_from_npy.npy");
+    let x = 1.0 / 2.0f64.sqrt();
+    // S * H, which isn't symmetric so a transposed read would be caught
+    let m = mat![[cplx(x, 0.0), cplx(x, 0.0)], [cplx(0.0, x), cplx(0.0, -x)]];
+    crate::npy::write_matrix(&path, &m).unwrap();
+    let g = Gate::from_npy(&path, vec![0]).unwrap();
+    assert!(mat_approx_eq(g.matrix(), &m));
+
+    let mut c = Circuit::new(1);
+    c.add_gate(g).unwrap();
+    let res = c.run().unwrap();
+    assert!(approx(res["0"], cplx(x, 0.0)));
+    assert!(approx(res["1"], cplx(0.0, x)));
+
+    // Wrong number of targets and non-unitary matrices are rejected
+    assert_eq!(
+        Gate::from_npy(&path, vec![0, 1]).unwrap_err(),
+        CircuitError::InvalidDimension(2, 2)
+    );
+    crate::npy::write_matrix(&path, &mat![[ONE, ONE], [ONE, ONE]]).unwrap();
+    assert_eq!(
+        Gate::from_npy(&path, vec![0]).unwrap_err(),
+        CircuitError::NonUnitary
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(
+        Gate::from_npy(&path, vec![0]),
+        Err(CircuitError::Npy(_))
+    ));
+}
+
+#[test]
+fn test_is_unit_checks_both_sides() {
+    // M = R Σ with R a 45° rotation and Σ = diag(√(1 + δ), 1): the error of
+    // M M† = R Σ² R† is spread over all entries, M† M = Σ² keeps it in one
+    let delta: f64 = 1.5e-5;
+    let r = 1.0 / 2.0f64.sqrt();
+    let s = (1.0 + delta).sqrt();
+    let m = mat![
+        [cplx(r * s, 0.0), cplx(-r, 0.0)],
+        [cplx(r * s, 0.0), cplx(r, 0.0)],
+    ];
+    let adjoint = m.adjoint().to_owned();
+    assert!(is_identity(&(&m * &adjoint)));
+    assert!(!is_identity(&(&adjoint * &m)));
+
+    assert!(!is_unit(&m));
+    assert!(Gate::new(m, vec![0]).is_none());
+}
+
+#[test]
+fn test_run_from_bits() {
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    let res = c.run_from_bits("1").unwrap();
+    assert!(approx(res["0"], ONE));
+    assert!(approx(res["1"], Z));
+
+    // Qubit 0 is the rightmost character
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    let res = c.run_from_bits("01").unwrap();
+    assert!(approx(res["00"], ONE));
+
+    for bits in ["0", "012", "0a", "101"] {
+        assert_eq!(
+            c.run_from_bits(bits).unwrap_err(),
+            CircuitError::InvalidBits(bits.to_string())
+        );
+    }
+}
+
+#[test]
+fn test_fredkin_truth_table() {
+    let g = Gate::fredkin(0, 1, 2).unwrap();
+    assert_eq!(g.name(), "cswap");
+    for input in 0..8 {
+        let mut state: Col<C> = Col::zeros(8);
+        state[input] = ONE;
+        g.apply(&mut state);
+
+        let (b1, b2) = ((input >> 1) & 1, (input >> 2) & 1);
+        let expected = if input & 1 == 1 {
+            (input & 1) | (b1 << 2) | (b2 << 1)
+        } else {
+            input
+        };
+        for i in 0..8 {
+            let want = if i == expected { ONE } else { Z };
+            assert!(approx(state[i], want));
+        }
+    }
+    assert!(mat_approx_eq(
+        &g.turn_big(3),
+        &Gate::fredkin(0, 2, 1).unwrap().turn_big(3)
+    ));
+}
+
+#[test]
+fn test_fredkin_wide_register() {
+    let mut c = Circuit::new(10);
+    c.x(9).unwrap();
+    c.x(3).unwrap();
+    c.fredkin(9, 3, 7).unwrap();
+    let state = c.statevector_sparse().unwrap();
+    assert!(approx(state[(1 << 9) | (1 << 7)], ONE));
+    assert!(approx(state[(1 << 9) | (1 << 3)], Z));
+
+    assert_eq!(c.swap(1, 1), Err(CircuitError::InvalidGate));
+    assert_eq!(c.fredkin(10, 1, 2), Err(CircuitError::QubitOutOfRange(10)));
+}
+
+#[test]
+fn test_bell_f64_backend() {
+    let mut fast: Circuit<c64> = Circuit::with_scalar(2);
+    fast.h(0).unwrap();
+    fast.cx(0, 1).unwrap();
+    let mut precise = Circuit::new(2);
+    precise.h(0).unwrap();
+    precise.cx(0, 1).unwrap();
+
+    let fast = fast.run().unwrap();
+    for (bits, amp) in precise.run().unwrap() {
+        assert!((fast[&bits].re - amp.re.0).abs() < 1e-12);
+        assert!((fast[&bits].im - amp.im.0).abs() < 1e-12);
+    }
+    assert!((fast["11"].re - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-12);
+}
+
+#[test]
+fn test_bell_f32_backend() {
+    let mut small: Circuit<c32> = Circuit::with_scalar(2);
+    small.h(0).unwrap();
+    small.cx(0, 1).unwrap();
+    let mut precise = Circuit::new(2);
+    precise.h(0).unwrap();
+    precise.cx(0, 1).unwrap();
+
+    let small = small.run().unwrap();
+    for (bits, amp) in precise.run().unwrap() {
+        assert!((small[&bits].re as f64 - amp.re.0).abs() < 1e-6);
+        assert!((small[&bits].im as f64 - amp.im.0).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_run_prefix() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let half = cplx(std::f64::consts::FRAC_1_SQRT_2, 0.0);
+    let state = c.run_prefix(1).unwrap();
+    assert!(approx(state[0], half));
+    assert!(approx(state[1], half));
+    assert!(approx(state[2], Z));
+    assert!(approx(state[3], Z));
+
+    assert!(approx(c.run_prefix(0).unwrap()[0], ONE));
+    assert!(approx(c.run_prefix(2).unwrap()[3], half));
+    assert_eq!(c.run_prefix(3), Err(CircuitError::GateCountOutOfRange(3)));
+}
+
+#[test]
+fn test_run_canonical_ignores_global_phase() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.t(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let mut shifted = c.clone();
+    let phase = cplx(0.6, 0.8);
+    shifted
+        .add_gate(Gate::new(mat![[phase, Z], [Z, phase]], vec![1]).unwrap())
+        .unwrap();
+
+    let a = c.run_canonical().unwrap();
+    let b = shifted.run_canonical().unwrap();
+    assert!(!approx(
+        c.run().unwrap()["00"],
+        shifted.run().unwrap()["00"]
+    ));
+    for (bits, amp) in &a {
+        assert!(approx(*amp, b[bits]));
+    }
+    assert!(approx(a["00"], cplx(std::f64::consts::FRAC_1_SQRT_2, 0.0)));
+}
+
+#[test]
+fn test_sx_gates() {
+    let sx = Gate::sx(0);
+    assert!(is_unit(sx.matrix()));
+    assert!(is_unit(Gate::sxdg(0).matrix()));
+    let twice = sx.matrix() * sx.matrix();
+    let x = Gate::x(0);
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(approx(twice[(i, j)], x.matrix()[(i, j)]));
+        }
+    }
+    assert!(is_identity(&(sx.matrix() * Gate::sxdg(0).matrix())));
+
+    let mut c = Circuit::new(1);
+    c.sx(0).unwrap();
+    c.sx(0).unwrap();
+    assert!(approx(c.run().unwrap()["1"], ONE));
+    assert_eq!(c.sxdg(1), Err(CircuitError::QubitOutOfRange(1)));
+}
+
+#[test]
+fn test_expectation() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    assert!((c.expectation("XX").unwrap() - 1.0).abs() < 1e-10);
+    assert!((c.expectation("YY").unwrap() + 1.0).abs() < 1e-10);
+    assert!(c.expectation("IZ").unwrap().abs() < 1e-10);
+
+    let mut plus_i = Circuit::new(1);
+    plus_i.h(0).unwrap();
+    plus_i.s(0).unwrap();
+    assert!((plus_i.expectation("Y").unwrap() - 1.0).abs() < 1e-10);
+    assert!(plus_i.expectation("X").unwrap().abs() < 1e-10);
+
+    assert_eq!(
+        c.expectation("XA"),
+        Err(CircuitError::InvalidPauli("XA".to_string()))
+    );
+    assert_eq!(
+        c.expectation("X"),
+        Err(CircuitError::InvalidPauli("X".to_string()))
+    );
+}
+
+#[test]
+fn test_parity() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    assert!((c.parity(&[0, 1]).unwrap() - 1.0).abs() < 1e-10);
+    assert!(c.parity(&[0]).unwrap().abs() < 1e-10);
+    c.x(2).unwrap();
+    assert!((c.parity(&[2]).unwrap() + 1.0).abs() < 1e-10);
+    assert_eq!(c.parity(&[3]), Err(CircuitError::QubitOutOfRange(3)));
+}
+
+#[test]
+fn test_circuit_add() {
+    let mut hadamard = Circuit::new(2);
+    hadamard.h(0).unwrap();
+    let mut cx = Circuit::new(2);
+    cx.cx(0, 1).unwrap();
+    let mut bell = Circuit::new(2);
+    bell.h(0).unwrap();
+    bell.cx(0, 1).unwrap();
+
+    assert!((hadamard.clone() + cx.clone()).equiv(&bell));
+    assert!(!(cx.clone() + hadamard.clone()).equiv(&bell));
+
+    let mut shifted = bell.clone();
+    shifted.z(0).unwrap();
+    shifted.x(0).unwrap();
+    shifted.z(0).unwrap();
+    shifted.x(0).unwrap();
+    assert!(shifted.equiv(&bell));
+
+    let mut c = hadamard.clone();
+    assert_eq!(
+        c.append(&Circuit::new(3)),
+        Err(CircuitError::QubitCountMismatch(2, 3))
+    );
+}
+
+#[test]
+#[should_panic]
+fn test_circuit_add_mismatched() {
+    let _ = Circuit::new(1) + Circuit::new(2);
+}
+
+#[test]
+fn test_ising_gates() {
+    assert!(is_identity(Gate::rzz(0.0, 0, 1).unwrap().matrix()));
+    assert!(is_identity(Gate::rxx(0.0, 0, 1).unwrap().matrix()));
+    assert!(is_identity(Gate::ryy(0.0, 0, 1).unwrap().matrix()));
+    let theta = 0.7;
+    assert!(is_unit(Gate::rxx(theta, 0, 1).unwrap().matrix()));
+    assert!(is_unit(Gate::ryy(theta, 0, 1).unwrap().matrix()));
+
+    let mut full_turn = Circuit::new(2);
+    full_turn.rzz(2.0 * std::f64::consts::PI, 0, 1).unwrap();
+    assert!(!is_identity(&full_turn.unitary().unwrap()));
+    assert!(full_turn.equiv(&Circuit::new(2)));
+
+    // At θ = π the interactions are X⊗X, Y⊗Y and Z⊗Z up to a phase
+    let pi = std::f64::consts::PI;
+    let mut xx = Circuit::new(2);
+    xx.rxx(pi, 0, 1).unwrap();
+    let mut expected = Circuit::new(2);
+    expected.x(0).unwrap();
+    expected.x(1).unwrap();
+    assert!(xx.equiv(&expected));
+    let mut yy = Circuit::new(2);
+    yy.ryy(pi, 1, 0).unwrap();
+    expected.z(0).unwrap();
+    expected.z(1).unwrap();
+    assert!(yy.equiv(&expected));
+
+    assert_eq!(full_turn.rzz(1.0, 1, 1), Err(CircuitError::InvalidGate));
+    assert_eq!(
+        full_turn.rxx(1.0, 0, 2),
+        Err(CircuitError::QubitOutOfRange(2))
+    );
+}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_send_sync() {
+    assert_send_sync::<Gate>();
+    assert_send_sync::<Circuit>();
+    assert_send_sync::<Circuit<c64>>();
+    assert_send_sync::<CircuitError>();
+}
+
+#[test]
+fn test_shared_circuit_across_threads() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.cx(1, 2).unwrap();
+    let expected = c.run().unwrap();
+    let shared = &c;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| shared.run().unwrap()))
+            .collect();
+        for handle in handles {
+            let res = handle.join().unwrap();
+            for (bits, amp) in &expected {
+                assert!(approx(res[bits], *amp));
+            }
+        }
+    });
+}
+
+#[test]
+fn test_add_gates_all_or_nothing() {
+    let mut c = Circuit::new(2);
+    c.add_gates(vec![Gate::h(0), Gate::cx(0, 1).unwrap()])
+        .unwrap();
+    assert_eq!(c.gates().len(), 2);
+
+    let res = c.add_gates(vec![Gate::x(1), Gate::x(5), Gate::z(0)]);
+    assert_eq!(res, Err(CircuitError::QubitOutOfRange(5)));
+    assert_eq!(c.gates().len(), 2);
+    assert!(approx(
+        c.run().unwrap()["11"],
+        cplx(std::f64::consts::FRAC_1_SQRT_2, 0.0)
+    ));
+}
+
+#[test]
+fn test_multi_controlled_swap() {
+    let g = Gate::multi_controlled(Gate::swap(2, 3).unwrap(), vec![0, 1]).unwrap();
+    assert_eq!(g.name(), "ccswap");
+    for input in 0..16usize {
+        let mut state: Col<C> = Col::zeros(16);
+        state[input] = ONE;
+        g.apply(&mut state);
+        let (b2, b3) = ((input >> 2) & 1, (input >> 3) & 1);
+        let expected = if input & 3 == 3 {
+            (input & 3) | (b2 << 3) | (b3 << 2)
+        } else {
+            input
+        };
+        for i in 0..16 {
+            let want = if i == expected { ONE } else { Z };
+            assert!(approx(state[i], want));
+        }
+    }
+
+    let swap = || Gate::swap(2, 3).unwrap();
+    assert!(Gate::multi_controlled(swap(), vec![]).is_none());
+    assert!(Gate::multi_controlled(swap(), vec![0, 0]).is_none());
+    assert!(Gate::multi_controlled(swap(), vec![0, 3]).is_none());
+}
+
+#[test]
+fn test_inner_product() {
+    let mut plus = Circuit::new(1);
+    plus.h(0).unwrap();
+    let plus = plus.statevector().unwrap();
+    let zero = Circuit::new(1).statevector().unwrap();
+    let half = cplx(std::f64::consts::FRAC_1_SQRT_2, 0.0);
+    assert!(approx(inner_product(&plus, &zero), half));
+    assert!(approx(inner_product(&zero, &plus), half));
+
+    let mut one = Circuit::new(1);
+    one.x(0).unwrap();
+    assert!(approx(inner_product(&zero, &one.statevector().unwrap()), Z));
+
+    // The first argument is conjugated
+    let mut plus_i = Circuit::new(1);
+    plus_i.h(0).unwrap();
+    plus_i.s(0).unwrap();
+    let plus_i = plus_i.statevector().unwrap();
+    assert!(approx(inner_product(&plus_i, &plus), cplx(0.5, -0.5)));
+}
+
+#[test]
+#[should_panic]
+fn test_inner_product_length_mismatch() {
+    let a = Circuit::new(1).statevector().unwrap();
+    let b = Circuit::new(2).statevector().unwrap();
+    inner_product(&a, &b);
+}
+
+#[test]
+fn test_repeated_gates_expanded_once() {
+    let mut c = Circuit::new(2);
+    for _ in 0..10 {
+        c.h(0).unwrap();
+    }
+    EXPANSIONS.with(|count| count.set(0));
+    let res = c.run().unwrap();
+    assert_eq!(EXPANSIONS.with(|count| count.get()), 1);
+    assert!(approx(res["00"], ONE));
+
+    // Interleaved gates are cached too, and the output is unchanged
+    let mut c = Circuit::new(2);
+    for _ in 0..5 {
+        c.h(0).unwrap();
+        c.x(1).unwrap();
+        c.t(0).unwrap();
+    }
+    c.cx(0, 1).unwrap();
+    EXPANSIONS.with(|count| count.set(0));
+    let state = c.statevector().unwrap();
+    assert_eq!(EXPANSIONS.with(|count| count.get()), 4);
+    let sparse = c.statevector_sparse().unwrap();
+    for i in 0..4 {
+        assert!(approx(state[i], sparse[i]));
+    }
+}
+
+#[test]
+fn test_apply_all() {
+    let mut c = Circuit::new(3);
+    c.apply_all(Gate::h).unwrap();
+    assert_eq!(c.gates().len(), 3);
+    let amp = cplx(1.0 / 8.0f64.sqrt(), 0.0);
+    let res = c.run().unwrap();
+    assert_eq!(res.len(), 8);
+    for x in res.values() {
+        assert!(approx(*x, amp));
+    }
+
+    let res = c.apply_all(|q| Gate::cx(q, q + 1).unwrap());
+    assert_eq!(res, Err(CircuitError::QubitOutOfRange(3)));
+    assert_eq!(c.gates().len(), 3);
+}
+
+#[test]
+fn test_repeat() {
+    let mut bell = Circuit::new(2);
+    bell.h(0).unwrap();
+    bell.cx(0, 1).unwrap();
+    let mut twice = bell.clone();
+    twice.h(0).unwrap();
+    twice.cx(0, 1).unwrap();
+
+    let repeated = bell.repeat(2);
+    assert_eq!(repeated.gates().len(), 4);
+    let expected = twice.run().unwrap();
+    for (bits, amp) in repeated.run().unwrap() {
+        assert!(approx(amp, expected[&bits]));
+    }
+
+    let empty = bell.repeat(0);
+    assert!(empty.gates().is_empty());
+    assert!(approx(empty.run().unwrap()["00"], ONE));
+}
+
+#[test]
+fn test_run_detects_collapse() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    // Projector on |0⟩, which annihilates the |1⟩ state of qubit 0
+    let projector = Gate::new_unchecked(mat![[ONE, Z], [Z, Z]], vec![0]);
+    c.add_gate(projector).unwrap();
+    assert_eq!(c.run(), Err(CircuitError::StateCollapsed));
+    assert_eq!(c.probabilities(), Err(CircuitError::StateCollapsed));
+}
+
+#[test]
+fn test_sample_shots() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let shots = c.sample_shots(200, 7).unwrap();
+    assert_eq!(shots.len(), 200);
+    assert!(shots.iter().all(|s| s == "00" || s == "11"));
+    let ones = shots.iter().filter(|s| *s == "11").count();
+    assert!(ones > 60 && ones < 140);
+    assert_eq!(c.sample_shots(10, 7).unwrap(), shots[..10]);
+    assert_ne!(c.sample_shots(200, 8).unwrap(), shots);
+
+    let mut x = Circuit::new(3);
+    x.x(2).unwrap();
+    assert_eq!(x.sample_shots(3, 0).unwrap(), vec!["100"; 3]);
+    assert!(x.sample_shots(0, 0).unwrap().is_empty());
+}
+
+#[test]
+fn test_apply_pairs_ghz_chain() {
+    let mut c = Circuit::new(4);
+    c.h(0).unwrap();
+    c.apply_pairs(Gate::cx, &[(0, 1), (1, 2), (2, 3)]).unwrap();
+    assert_eq!(c.gates().len(), 4);
+    let half = cplx(std::f64::consts::FRAC_1_SQRT_2, 0.0);
+    for (bits, amp) in c.run().unwrap() {
+        let want = if bits == "0000" || bits == "1111" {
+            half
+        } else {
+            Z
+        };
+        assert!(approx(amp, want));
+    }
+
+    assert_eq!(
+        c.apply_pairs(Gate::cx, &[(0, 1), (2, 2)]),
+        Err(CircuitError::InvalidGate)
+    );
+    assert_eq!(
+        c.apply_pairs(Gate::cx, &[(0, 1), (3, 4)]),
+        Err(CircuitError::QubitOutOfRange(4))
+    );
+    assert_eq!(c.gates().len(), 4);
+}
+
+#[test]
+fn test_trace() {
+    let id: Mat<C> = Mat::identity(4, 4);
+    assert!(approx(trace(&id), cplx(4.0, 0.0)));
+    assert!(approx(trace(Gate::x(0).matrix()), Z));
+    assert!(approx(trace(Gate::s(0).matrix()), cplx(1.0, 1.0)));
+}
+
+#[test]
+#[should_panic]
+fn test_trace_non_square() {
+    let m: Mat<C> = Mat::zeros(2, 3);
+    trace(&m);
+}
+
+#[test]
+fn test_format_result_opts() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    let mut res = c.run().unwrap();
+    res.retain(|_, x| norm(*x) > 0.5);
+
+    let default = format_result_opts(&res, DisplayOptions::default());
+    assert_eq!(default, "|01⟩:  1.00000 + i0.00000");
+    let reversed = DisplayOptions {
+        reverse_bits: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        format_result_opts(&res, reversed),
+        "|10⟩:  1.00000 + i0.00000"
+    );
+    let bra = DisplayOptions {
+        bracket: Bracket::Bra,
+        reverse_bits: true,
+        ..Default::default()
+    };
+    assert_eq!(format_result_opts(&res, bra), "⟨10|:  1.00000 + i0.00000");
+
+    // Lines are sorted by the printed label
+    let full = format_result_opts(&c.run().unwrap(), reversed);
+    let labels: Vec<&str> = full.lines().map(|l| &l[1..3]).collect();
+    assert_eq!(labels, ["00", "01", "10", "11"]);
+}
+
+#[test]
+fn test_from_permutation() {
+    let g = Gate::from_permutation(&[0, 1, 3, 2], vec![0, 1]).unwrap();
+    assert!(mat_approx_eq(
+        &g.turn_big(2),
+        &Gate::cx(1, 0).unwrap().turn_big(2)
+    ));
+
+    let mut c = Circuit::new(3);
+    c.x(2).unwrap();
+    // Cyclic increment on qubits 1 and 2
+    c.add_gate(Gate::from_permutation(&[1, 2, 3, 0], vec![1, 2]).unwrap())
+        .unwrap();
+    assert!(approx(c.run().unwrap()["110"], ONE));
+
+    assert!(Gate::from_permutation(&[0, 1, 1, 2], vec![0, 1]).is_none());
+    assert!(Gate::from_permutation(&[0, 1, 2, 4], vec![0, 1]).is_none());
+    assert!(Gate::from_permutation(&[1, 0], vec![0, 1]).is_none());
+}
+
+#[test]
+fn test_assert_state_approx() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    let state = c.statevector().unwrap();
+    assert_state_approx(&state, &c.statevector_sparse().unwrap(), 1e-10);
+}
+
+#[test]
+#[should_panic(expected = "states differ at index 0")]
+fn test_assert_state_approx_mismatch() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    let state = c.statevector().unwrap();
+    assert_state_approx(&state, &Circuit::new(2).statevector().unwrap(), 1e-10);
+}
+
+#[test]
+fn test_cost_model() {
+    let mut small = Circuit::new(2);
+    small.cx(0, 1).unwrap();
+    small.rzz(0.3, 1, 0).unwrap();
+    let mut wide = Circuit::new(20);
+    wide.cx(0, 19).unwrap();
+    wide.rzz(0.3, 7, 12).unwrap();
+    assert_eq!(small.cost_model(), 8);
+    assert_eq!(wide.cost_model(), 8);
+
+    wide.cnx(vec![1, 2, 3, 4], 5).unwrap();
+    wide.h(0).unwrap();
+    assert_eq!(wide.cost_model(), 8 + 32 + 2);
+    assert_eq!(Circuit::new(3).cost_model(), 0);
+}
+
+#[test]
+fn test_phase_gate() {
+    use std::f64::consts::PI;
+    assert!(mat_approx_eq(Gate::p(PI, 0).matrix(), Gate::z(0).matrix()));
+    assert!(mat_approx_eq(
+        Gate::p(PI / 2.0, 0).matrix(),
+        Gate::s(0).matrix()
+    ));
+    assert!(mat_approx_eq(
+        Gate::p(PI / 4.0, 0).matrix(),
+        Gate::t(0).matrix()
+    ));
+    assert!(is_identity(Gate::p(0.0, 0).matrix()));
+
+    let mut c = Circuit::new(1);
+    c.h(0).unwrap();
+    c.p(PI / 3.0, 0).unwrap();
+    let phases = c.phases().unwrap();
+    assert!((phases["1"] - PI / 3.0).abs() < 1e-10);
+    assert_eq!(c.p(1.0, 1), Err(CircuitError::QubitOutOfRange(1)));
+}
+
+#[test]
+fn test_unitary_gate() {
+    let mut c = Circuit::new(2);
+    // iX, a bit flip with a global phase
+    c.unitary_gate(mat![[Z, IM], [IM, Z]], vec![1]).unwrap();
+    assert!(approx(c.run().unwrap()["10"], IM));
+
+    assert_eq!(
+        c.unitary_gate(mat![[ONE, ONE], [Z, ONE]], vec![0]),
+        Err(CircuitError::NonUnitary)
+    );
+    assert_eq!(
+        c.unitary_gate(mat![[ONE, Z], [Z, ONE]], vec![0, 1]),
+        Err(CircuitError::InvalidDimension(2, 2))
+    );
+    assert_eq!(
+        c.unitary_gate(mat![[ONE, Z], [Z, ONE]], vec![2]),
+        Err(CircuitError::QubitOutOfRange(2))
+    );
+    assert_eq!(c.gates().len(), 1);
+}
+
+#[test]
+fn test_global_phase() {
+    use std::f64::consts::PI;
+    let mut c = Circuit::new(2);
+    c.x(1).unwrap();
+    c.global_phase(PI / 3.0).unwrap();
+    assert!((c.global_phase_of().unwrap() - PI / 3.0).abs() < 1e-10);
+    assert!(approx(
+        c.statevector_sparse().unwrap()[2],
+        cplx(0.5, 3.0f64.sqrt() / 2.0)
+    ));
+
+    // Superpositions fall back to the explicit global_phase gates
+    let mut c = Circuit::new(1);
+    c.h(0).unwrap();
+    c.global_phase(PI / 3.0).unwrap();
+    c.global_phase(PI / 2.0).unwrap();
+    assert!((c.global_phase_of().unwrap() - 5.0 * PI / 6.0).abs() < 1e-10);
+    assert!(c.equiv(&{
+        let mut h = Circuit::new(1);
+        h.h(0).unwrap();
+        h
+    }));
+
+    let mut z = Circuit::new(1);
+    z.x(0).unwrap();
+    z.z(0).unwrap();
+    assert!((z.global_phase_of().unwrap() - PI).abs() < 1e-10);
+}
+
+#[test]
+fn test_qft() {
+    let n = 3;
+    let size = 8;
+    let u = Circuit::qft(n).unitary().unwrap();
+    for k in 0..size {
+        for x in 0..size {
+            let angle = 2.0 * std::f64::consts::PI * (x * k) as f64 / size as f64;
+            let want = cplx(angle.cos() / 8f64.sqrt(), angle.sin() / 8f64.sqrt());
+            assert!(approx(u[(k, x)], want));
+        }
+    }
+}
+
+#[test]
+fn test_qft_no_swap_reverses_qubits() {
+    let reverse = |bits: &str| bits.chars().rev().collect::<String>();
+    for input in ["000", "001", "110"] {
+        let swapped = Circuit::qft(3).run_from_bits(input).unwrap();
+        let unswapped = Circuit::qft_no_swap(3).run_from_bits(input).unwrap();
+        for (bits, amp) in &swapped {
+            assert!(approx(unswapped[&reverse(bits)], *amp));
+        }
+    }
+}
+
+#[test]
+fn test_expectation_sampled() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.t(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.h(1).unwrap();
+    for pauli in ["XX", "IY", "ZI", "YZ"] {
+        let exact = c.expectation(pauli).unwrap();
+        let (few, few_err) = c.expectation_sampled(pauli, 100, 1).unwrap();
+        let (many, many_err) = c.expectation_sampled(pauli, 10000, 1).unwrap();
+        assert!(many_err < few_err || few_err == 0.0);
+        assert!((many - exact).abs() < 5.0 * many_err + 1e-10);
+        assert!((few - exact).abs() < 5.0 * few_err + 1e-10);
+    }
+
+    let mut z = Circuit::new(1);
+    z.x(0).unwrap();
+    assert_eq!(z.expectation_sampled("Z", 50, 0), Ok((-1.0, 0.0)));
+    assert_eq!(
+        z.expectation_sampled("Z", 1, 0),
+        Err(CircuitError::InvalidShots(1))
+    );
+    assert_eq!(
+        z.expectation_sampled("ZZ", 10, 0),
+        Err(CircuitError::InvalidPauli("ZZ".to_string()))
+    );
+}
+
+#[test]
+fn test_gates_act_in_push_order() {
+    let half = std::f64::consts::FRAC_1_SQRT_2;
+    // X then H: |0⟩ -> |1⟩ -> |−⟩
+    let mut xh = Circuit::new(1);
+    xh.x(0).unwrap();
+    xh.h(0).unwrap();
+    assert_eq!(xh.gate_order(), ["x", "h"]);
+    // H then X: |0⟩ -> |+⟩ -> |+⟩
+    let mut hx = Circuit::new(1);
+    hx.h(0).unwrap();
+    hx.x(0).unwrap();
+    assert_eq!(hx.gate_order(), ["h", "x"]);
+
+    for state in [
+        xh.statevector().unwrap(),
+        xh.statevector_sparse().unwrap(),
+        xh.run_layered().unwrap(),
+    ] {
+        assert!(approx(state[0], cplx(half, 0.0)));
+        assert!(approx(state[1], cplx(-half, 0.0)));
+    }
+    for state in [
+        hx.statevector().unwrap(),
+        hx.statevector_sparse().unwrap(),
+        hx.run_layered().unwrap(),
+    ] {
+        assert!(approx(state[0], cplx(half, 0.0)));
+        assert!(approx(state[1], cplx(half, 0.0)));
+    }
+}
+
+#[test]
+fn test_apply_strategies() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.t(0).unwrap();
+    c.cx(0, 2).unwrap();
+    c.rzz(0.4, 1, 2).unwrap();
+    c.fredkin(2, 0, 1).unwrap();
+    c.sx(1).unwrap();
+
+    let dense = c.statevector().unwrap();
+    for strategy in [Strategy::Sparse, Strategy::Auto] {
+        let mut other = c.clone();
+        other.set_apply_strategy(strategy);
+        let state = other.statevector().unwrap();
+        for i in 0..8 {
+            assert!(approx(state[i], dense[i]));
+        }
+        assert!(approx(other.run().unwrap()["000"], dense[0]));
+    }
+
+    assert_eq!(Strategy::Auto.resolve(1, 5), Strategy::Sparse);
+    assert_eq!(Strategy::Auto.resolve(2, 5), Strategy::Sparse);
+    assert_eq!(Strategy::Auto.resolve(3, 3), Strategy::Dense);
+    assert_eq!(Strategy::Dense.resolve(1, 5), Strategy::Dense);
+
+    // Sparse gates never build an expansion
+    let mut narrow = Circuit::new(4);
+    narrow.h(0).unwrap();
+    narrow.cx(0, 3).unwrap();
+    narrow.set_apply_strategy(Strategy::Auto);
+    EXPANSIONS.with(|count| count.set(0));
+    narrow.run().unwrap();
+    assert_eq!(EXPANSIONS.with(|count| count.get()), 0);
+}
+
+#[test]
+fn test_run_indexed() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    c.x(1).unwrap();
+    let amps = c.run_indexed().unwrap();
+    assert_eq!(amps.len(), 4);
+    assert!(approx(amps[3], ONE));
+    assert!(approx(amps[3], c.run().unwrap()["11"]));
+
+    c.h(1).unwrap();
+    let amps = c.run_indexed().unwrap();
+    for (bits, amp) in c.run().unwrap() {
+        assert!(approx(amps[usize::from_str_radix(&bits, 2).unwrap()], amp));
+    }
+}
+
+#[test]
+fn test_is_diagonal() {
+    assert!(Gate::z(0).is_diagonal());
+    assert!(Gate::s(0).is_diagonal());
+    assert!(Gate::cp(0.3, 0, 1).unwrap().is_diagonal());
+    assert!(Gate::rzz(0.3, 0, 1).unwrap().is_diagonal());
+    assert!(!Gate::h(0).is_diagonal());
+    assert!(!Gate::x(0).is_diagonal());
+    assert!(!Gate::cx(0, 1).unwrap().is_diagonal());
+}
+
+#[test]
+fn test_mcrz() {
+    let mut c = Circuit::new(3);
+    for q in 0..3 {
+        c.h(q).unwrap();
+    }
+    c.add_gate(Gate::mcrz(std::f64::consts::PI, vec![0, 1], 2).unwrap())
+        .unwrap();
+    let res = c.run().unwrap();
+    let a = 1.0 / 8.0f64.sqrt();
+    for (bits, amp) in &res {
+        let expected = match bits.as_str() {
+            "011" => cplx(0.0, -a),
+            "111" => cplx(0.0, a),
+            _ => cplx(a, 0.0),
+        };
+        assert!(approx(*amp, expected), "{}: {:?}", bits, amp);
+    }
+
+    // No controls is the plain rotation
+    let plain = Gate::mcry(0.4, vec![], 0).unwrap();
+    assert_eq!(plain.name(), "ry");
+    assert!(mat_approx_eq(plain.matrix(), Gate::ry(0.4, 0).matrix()));
+
+    assert!(Gate::mcrx(0.4, vec![1, 1], 0).is_none());
+    assert!(Gate::mcrz(0.4, vec![0, 1], 0).is_none());
+}
+
+#[test]
+fn test_run_with_parameters() {
+    let mut c = Circuit::new(1);
+    c.rx(Parameter::Param("theta".to_string()), 0).unwrap();
+    assert_eq!(
+        c.run().unwrap_err(),
+        CircuitError::UnboundParameter("theta".to_string())
+    );
+    let other: HashMap<String, f64> = [("phi".to_string(), 1.0)].into();
+    assert_eq!(
+        c.run_with(&other).unwrap_err(),
+        CircuitError::UnboundParameter("theta".to_string())
+    );
+
+    for theta in [0.0, 0.5, 1.0, std::f64::consts::PI] {
+        let bindings: HashMap<String, f64> = [("theta".to_string(), theta)].into();
+        let res = c.run_with(&bindings).unwrap();
+        assert!(approx(res["0"], cplx((theta / 2.0).cos(), 0.0)));
+        assert!(approx(res["1"], cplx(0.0, -(theta / 2.0).sin())));
+    }
+
+    // Fixed angles need no binding, and the inverse of a parametric rotation
+    // is bound to the opposite angle
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.ry(0.3, 1).unwrap();
+    c.rz(Parameter::Param("theta".to_string()), 0).unwrap();
+    c.add_gate(c.gates()[2].dagger()).unwrap();
+    let bindings: HashMap<String, f64> = [("theta".to_string(), 0.7)].into();
+    let res = c.run_with(&bindings).unwrap();
+    let mut expected = Circuit::new(2);
+    expected.h(0).unwrap();
+    expected.ry(0.3, 1).unwrap();
+    for (bits, amp) in expected.run().unwrap() {
+        assert!(approx(res[&bits], amp));
+    }
+}
+
+#[test]
+fn test_diff() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.x(1).unwrap();
+    c.h(1).unwrap();
+    c.h(1).unwrap();
+    c.x(1).unwrap();
+    c.cx(0, 1).unwrap();
+    let cancelled = c.cancel_inverses();
+    assert_eq!(
+        c.diff(&cancelled),
+        vec!["- 1: x [1]", "- 2: h [1]", "- 3: h [1]", "- 4: x [1]"]
+    );
+    assert!(c.diff(&c).is_empty());
+
+    let mut other = Circuit::new(2);
+    other.h(0).unwrap();
+    other.z(1).unwrap();
+    assert_eq!(cancelled.diff(&other), vec!["- 1: cx [1, 0]", "+ 1: z [1]"]);
+}
+
+#[test]
+fn test_compact() {
+    let mut c = Circuit::new(6);
+    c.h(5).unwrap();
+    c.cx(5, 0).unwrap();
+    assert_eq!(c.effective_qubits(), vec![0, 5]);
+
+    let (small, mapping) = c.compact();
+    assert_eq!(small.num_qubits(), 2);
+    assert_eq!(mapping, vec![0, 5]);
+    assert_eq!(small.gates()[1].qubits(), &[0, 1]);
+
+    // Amplitudes match once the idle qubits are put back in |0⟩
+    let res = small.run().unwrap();
+    let full = c.run().unwrap();
+    for (bits, amp) in &res {
+        let mut index = 0;
+        for (i, b) in bits.chars().rev().enumerate() {
+            if b == '1' {
+                index |= 1 << mapping[i];
+            }
+        }
+        assert!(approx(*amp, full[&format!("{:06b}", index)]));
+    }
+
+    assert!(Circuit::new(3).effective_qubits().is_empty());
+}
+
+#[test]
+fn test_probability_ranking() {
+    let mut c = Circuit::new(2);
+    c.ry(1.0, 0).unwrap();
+    c.h(1).unwrap();
+    let ranking = c.probability_ranking().unwrap();
+    assert_eq!(ranking.len(), 4);
+
+    let p0 = (0.5f64).cos().powi(2) / 2.0;
+    assert_eq!(ranking[0].0, "00");
+    assert!((ranking[0].1 - p0).abs() < 1e-10);
+    assert_eq!(ranking[1].0, "10");
+    assert!(ranking.windows(2).all(|w| w[0].1 >= w[1].1));
+    let total: f64 = ranking.iter().map(|(_, p)| p).sum();
+    assert!((total - 1.0).abs() < 1e-10);
+}
+
+#[test]
+fn test_ccz() {
+    let mut c = Circuit::new(3);
+    for q in 0..3 {
+        c.h(q).unwrap();
+    }
+    c.ccz(0, 1, 2).unwrap();
+    let a = 1.0 / 8.0f64.sqrt();
+    for (bits, amp) in c.run().unwrap() {
+        let sign = if bits == "111" { -1.0 } else { 1.0 };
+        assert!(approx(amp, cplx(sign * a, 0.0)));
+    }
+
+    let base = Gate::ccz(0, 1, 2).unwrap().turn_big(3);
+    for (c1, c2, t) in [(2, 1, 0), (0, 2, 1), (1, 2, 0)] {
+        let other = Gate::ccz(c1, c2, t).unwrap().turn_big(3);
+        assert!(mat_approx_eq(&base, &other));
+    }
+    assert!(Gate::ccz(0, 0, 1).is_none());
+}
+
+#[test]
+fn test_ancilla() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    c.h(1).unwrap();
+    let anc = c.alloc_ancilla();
+    assert_eq!(anc, 2);
+    assert_eq!(c.num_qubits(), 3);
+
+    // Copy qubit 0 into the ancilla, use it as a control, then uncompute it
+    c.cx(0, anc).unwrap();
+    assert_eq!(c.free_ancilla(anc), Err(CircuitError::DirtyAncilla(anc)));
+    c.z(1).unwrap();
+    c.cx(anc, 1).unwrap();
+    c.cx(0, anc).unwrap();
+    c.free_ancilla(anc).unwrap();
+    assert_eq!(c.alloc_ancilla(), anc);
+
+    let res = c.run().unwrap();
+    let a = 1.0 / 2.0f64.sqrt();
+    assert!(approx(res["001"], cplx(-a, 0.0)));
+    assert!(approx(res["011"], cplx(a, 0.0)));
+    assert!(approx(res["101"], Z));
+}
+
+#[test]
+fn test_is_unitary() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    assert!(c.is_unitary());
+
+    let mut corrupted = Gate::h(0).turn_big(1);
+    corrupted[(0, 1)] = ONE;
+    let fused = Gate::new_unchecked(corrupted, vec![0]).named("fused");
+    c.add_gate(fused).unwrap();
+    assert!(!c.is_unitary());
+}
+
+#[test]
+fn test_energy() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let ham = Hamiltonian::new(vec![(0.5, "ZZ".to_string()), (0.5, "II".to_string())]);
+    assert!((c.energy(&ham).unwrap() - 1.0).abs() < 1e-10);
+
+    let ham = Hamiltonian::new(vec![(2.0, "XX".to_string()), (-1.0, "ZI".to_string())]);
+    assert!((c.energy(&ham).unwrap() - 2.0).abs() < 1e-10);
+
+    let bad = Hamiltonian::new(vec![(1.0, "ZZ".to_string()), (1.0, "Z".to_string())]);
+    assert_eq!(
+        c.energy(&bad),
+        Err(CircuitError::InvalidPauli("Z".to_string()))
+    );
+}
+
+#[test]
+fn test_with_fixed() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(1, 2).unwrap();
+    c.cnx(vec![0, 1], 2).unwrap();
+
+    let reduced = c.with_fixed(&[(1, 1)].into()).unwrap();
+    assert_eq!(reduced.num_qubits(), 2);
+    assert_eq!(reduced.gate_order(), vec!["h", "x", "cx"]);
+    assert_eq!(reduced.gates()[1].qubits(), &[1]);
+    assert!(mat_approx_eq(
+        reduced.gates()[1].matrix(),
+        Gate::x(0).matrix()
+    ));
+    assert_eq!(reduced.gates()[2].qubits(), &[1, 0]);
+
+    // A control pinned to 0 disables the gate
+    let reduced = c.with_fixed(&[(1, 0)].into()).unwrap();
+    assert_eq!(reduced.gate_order(), vec!["h"]);
+
+    assert_eq!(
+        c.with_fixed(&[(2, 1)].into()).unwrap_err(),
+        CircuitError::FixedQubitTargeted(2)
+    );
+    assert_eq!(
+        c.with_fixed(&[(1, 2)].into()).unwrap_err(),
+        CircuitError::InvalidBits("2".to_string())
+    );
+}
+
+#[test]
+fn test_format_scientific() {
+    let res: HashMap<String, C> = [
+        ("0".to_string(), cplx(1e-8, 0.0)),
+        ("1".to_string(), cplx(0.5, -0.5)),
+    ]
+    .into();
+    let fixed = format_result_opts(&res, DisplayOptions::default());
+    assert_eq!(fixed.lines().next().unwrap(), "|0⟩:  0.00000 + i0.00000");
+
+    let auto = DisplayOptions {
+        numbers: NumberFormat::Auto {
+            min: 1e-4,
+            max: 1e4,
+        },
+        ..Default::default()
+    };
+    assert_eq!(
+        format_result_opts(&res, auto),
+        "|0⟩:  1.00000e-8 + i0.00000e0\n|1⟩:  0.50000 - i0.50000"
+    );
+}
+
+#[test]
+fn test_from_ops() {
+    let ops = [
+        ("h".to_string(), vec![], vec![0]),
+        ("cx".to_string(), vec![], vec![0, 1]),
+    ];
+    let c = Circuit::from_ops(2, &ops).unwrap();
+    let mut bell = Circuit::new(2);
+    bell.h(0).unwrap();
+    bell.cx(0, 1).unwrap();
+    assert_eq!(c.gate_order(), bell.gate_order());
+    assert!(mat_approx_eq(
+        &c.unitary().unwrap(),
+        &bell.unitary().unwrap()
+    ));
+
+    let c = Circuit::from_ops(1, &[("rx".to_string(), vec![0.3], vec![0])]).unwrap();
+    assert!(mat_approx_eq(
+        c.gates()[0].matrix(),
+        Gate::rx(0.3, 0).matrix()
+    ));
+
+    let err = |name: &str, params: Vec<f64>, qubits: Vec<usize>| {
+        Circuit::from_ops(2, &[(name.to_string(), params, qubits)]).unwrap_err()
+    };
+    assert_eq!(
+        err("rx", vec![], vec![0]),
+        CircuitError::UnknownGate("rx".to_string(), 0, 1)
+    );
+    assert_eq!(
+        err("h", vec![], vec![0, 1]),
+        CircuitError::UnknownGate("h".to_string(), 0, 2)
+    );
+    assert_eq!(
+        err("foo", vec![], vec![0]),
+        CircuitError::UnknownGate("foo".to_string(), 0, 1)
+    );
+    assert_eq!(err("x", vec![], vec![2]), CircuitError::QubitOutOfRange(2));
+}
+
+#[test]
+fn test_run_trajectory() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.rz(0.4, 1).unwrap();
+    let state = c.run_trajectory(&NoiseModel::new(0.0), 7).unwrap();
+    assert_state_approx(&state, &c.statevector().unwrap(), 1e-10);
+
+    // X then a depolarizing error with probability p: X and Y errors send the
+    // qubit back to |0⟩, so P(1) = 1 - 2p/3
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    let noise = NoiseModel::new(0.3);
+    let runs = 2000;
+    let p1: f64 = (0..runs)
+        .map(|seed| {
+            let state = c.run_trajectory(&noise, seed).unwrap();
+            norm(state[1]) * norm(state[1])
+        })
+        .sum::<f64>()
+        / runs as f64;
+    assert!((p1 - 0.8).abs() < 0.03, "{}", p1);
+}
+
+#[test]
+fn test_format_histogram() {
+    let counts: HashMap<String, usize> = [
+        ("00".to_string(), 50),
+        ("11".to_string(), 100),
+        ("1".to_string(), 25),
+    ]
+    .into();
+    let chart = format_histogram(&counts, 20);
+    assert_eq!(
+        chart,
+        "00 | ########## 50\n 1 | ##### 25\n11 | #################### 100"
+    );
+    let longest = chart
+        .lines()
+        .max_by_key(|l| l.matches('#').count())
+        .unwrap();
+    assert!(longest.starts_with("11"));
+    assert_eq!(format_histogram(&HashMap::new(), 10), "");
+}
+
+#[test]
+fn test_margolus() {
+    let margolus = Gate::margolus(0, 1, 2).unwrap();
+    let ccx = Gate::cnx(vec![0, 1], 2).unwrap();
+    let mut differences = Vec::new();
+    for input in 0..8 {
+        let mut a: Col<C> = Col::zeros(8);
+        a[input] = ONE;
+        let mut b = a.clone();
+        margolus.apply(&mut a);
+        ccx.apply(&mut b);
+        if !(0..8).all(|i| approx(a[i], b[i])) {
+            // Only the phase differs
+            assert!(approx(a[input], -b[input]));
+            differences.push(input);
+        }
+    }
+    // c1 = 1, c2 = 0, target = 1
+    assert_eq!(differences, vec![0b101]);
+    assert!(Gate::margolus(0, 0, 1).is_none());
+}
+
+#[test]
+fn test_transpile() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let t = c.transpile(&["h", "cz"]).unwrap();
+    assert_eq!(t.gate_order(), vec!["h", "h", "cz", "h"]);
+    assert!(c.equiv(&t));
+
+    let mut toffoli = Circuit::new(3);
+    toffoli.cnx(vec![0, 2], 1).unwrap();
+    let t = toffoli.transpile(&["h", "t", "cx"]).unwrap();
+    assert!(
+        t.gate_order()
+            .iter()
+            .all(|name| ["h", "t", "cx"].contains(name))
+    );
+    assert!(toffoli.equiv(&t));
+
+    assert_eq!(
+        c.transpile(&["h", "x"]).unwrap_err(),
+        CircuitError::NoDecomposition("cx".to_string())
+    );
+    c.rx(0.3, 1).unwrap();
+    assert_eq!(
+        c.transpile(&["h", "cz"]).unwrap_err(),
+        CircuitError::NoDecomposition("rx".to_string())
+    );
+}
+
+#[test]
+fn test_approx_eq() {
+    let old = |a: C, b: C| norm(a - b) < 1e-5;
+    let pairs = [
+        (ONE, ONE),
+        (ONE, cplx(1.0 + 1e-7, -1e-7)),
+        (IM, cplx(1e-6, 1.0)),
+        (Z, cplx(2e-5, 0.0)),
+        (ONE, -ONE),
+        (cplx(0.5, 0.5), cplx(0.5, 0.50001)),
+    ];
+    for (a, b) in pairs {
+        assert_eq!(approx_eq(a, b, TOLERANCE), old(a, b));
+        assert_eq!(approx_eq(a, b, TOLERANCE), approx_eq(b, a, TOLERANCE));
+    }
+    assert!(approx_eq(ONE, cplx(1.1, 0.0), 0.2));
+    assert!(!approx_eq(c64::new(1.0, 0.0), c64::new(1.0, 1e-3), 1e-4));
+}
+
+#[test]
+fn test_phase_oracle() {
+    let oracle = Gate::phase_oracle(&["101".to_string()], 3).unwrap();
+    assert!(oracle.is_diagonal());
+    let mut c = Circuit::new(3);
+    for q in 0..3 {
+        c.h(q).unwrap();
+    }
+    c.add_gate(oracle).unwrap();
+    let a = 1.0 / 8.0f64.sqrt();
+    for (bits, amp) in c.run().unwrap() {
+        let sign = if bits == "101" { -1.0 } else { 1.0 };
+        assert!(approx(amp, cplx(sign * a, 0.0)));
+    }
+
+    assert!(Gate::phase_oracle(&["10".to_string()], 3).is_none());
+    assert!(Gate::phase_oracle(&["1a1".to_string()], 3).is_none());
+    assert!(Gate::phase_oracle(&[], 2).is_some());
+}
+
+#[test]
+fn test_run_chunked() {
+    let mut c = Circuit::new(4);
+    c.h(0).unwrap();
+    c.h(3).unwrap();
+    c.cx(3, 1).unwrap();
+    c.cp(0.7, 0, 3).unwrap();
+    c.cx(0, 2).unwrap();
+    c.t(2).unwrap();
+    c.rzz(0.3, 1, 3).unwrap();
+    let expected = c.statevector().unwrap();
+    for chunk_bits in [0, 1, 2, 4, 6] {
+        let mut state = Vec::new();
+        let mut chunks = Vec::new();
+        c.run_chunked(chunk_bits, |k, amps| {
+            chunks.push(k);
+            state.extend_from_slice(amps);
+        })
+        .unwrap();
+        let count = 1 << (4 - chunk_bits.min(4));
+        assert_eq!(chunks, (0..count).collect::<Vec<_>>());
+        assert_state_approx(&Col::from_fn(16, |i| state[i]), &expected, 1e-10);
+    }
+
+    // Only chunk-local gates: every chunk but the first stays zero
+    let mut c = Circuit::new(4);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.t(1).unwrap();
+    c.cz(1, 3).unwrap();
+    let expected = c.statevector().unwrap();
+    let mut state = Vec::new();
+    c.run_chunked(2, |_, amps| state.extend_from_slice(amps))
+        .unwrap();
+    assert_state_approx(&Col::from_fn(16, |i| state[i]), &expected, 1e-10);
+}
+
+#[test]
+fn test_fuse_controlled_phases() {
+    let quarter = std::f64::consts::FRAC_PI_4;
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.h(1).unwrap();
+    c.cp(quarter, 0, 1).unwrap();
+    c.cp(quarter, 0, 1).unwrap();
+    let fused = c.fuse();
+    assert_eq!(fused.gate_order(), vec!["h", "h", "fused"]);
+    let merged = &fused.gates()[2];
+    assert!(merged.is_diagonal());
+    assert_eq!(merged.controls(), &[0]);
+    let half = Gate::cp(2.0 * quarter, 0, 1).unwrap();
+    assert!(mat_approx_eq(&merged.turn_big(3), &half.turn_big(3)));
+    assert!(c.equiv(&fused));
+
+    // The control and target of the second phase are swapped
+    let mut c = Circuit::new(2);
+    c.cp(quarter, 0, 1).unwrap();
+    c.cp(quarter, 1, 0).unwrap();
+    c.h(1).unwrap();
+    let fused = c.fuse();
+    assert_eq!(fused.gates().len(), 2);
+    assert!(mat_approx_eq(
+        &fused.gates()[0].turn_big(2),
+        &half.turn_big(2)
+    ));
+    assert!(c.equiv(&fused));
+}
+
+#[test]
+fn test_check_connectivity() {
+    let linear = [(0, 1), (1, 2)];
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 2).unwrap();
+    assert_eq!(
+        c.check_connectivity(&linear),
+        Err(CircuitError::Uncoupled(1, "cx".to_string()))
+    );
+
+    // Bring qubit 0 next to qubit 2 and back
+    let mut routed = Circuit::new(3);
+    routed.h(0).unwrap();
+    routed.swap(0, 1).unwrap();
+    routed.cx(1, 2).unwrap();
+    routed.swap(0, 1).unwrap();
+    routed.check_connectivity(&linear).unwrap();
+    assert!(routed.equiv(&c));
+
+    let mut wide = Circuit::new(3);
+    wide.ccz(0, 1, 2).unwrap();
+    assert!(wide.check_connectivity(&linear).is_err());
+}
+
+#[test]
+fn test_route() {
+    let line = [(0, 1), (1, 2), (2, 3)];
+    let mut c = Circuit::new(4);
+    c.h(0).unwrap();
+    c.ry(0.4, 3).unwrap();
+    c.cx(0, 3).unwrap();
+    c.rz(0.9, 0).unwrap();
+    c.cx(3, 1).unwrap();
+    assert!(c.check_connectivity(&line).is_err());
+
+    let (routed, layout) = c.route_with_layout(&line).unwrap();
+    routed.check_connectivity(&line).unwrap();
+    assert_eq!(
+        routed.gate_order().iter().filter(|n| **n == "swap").count(),
+        2
+    );
+    assert_eq!(layout, vec![0, 2, 3, 1]);
+
+    let original = c.statevector().unwrap();
+    let state = routed.statevector().unwrap();
+    for x in 0..16 {
+        let y = (0..4).fold(0, |acc, l| acc | (((x >> l) & 1) << layout[l]));
+        assert!(approx(original[x], state[y]));
+    }
+    assert!(states_equal_up_to_permutation(&original, &state, &layout));
+
+    assert_eq!(
+        c.route(&[(0, 1), (2, 3)]).unwrap_err(),
+        CircuitError::Uncoupled(2, "cx".to_string())
+    );
+}
+
+#[test]
+fn test_trace_states() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let states = c.trace_states().unwrap();
+    assert_eq!(states.len(), 3);
+    assert!(approx(states[0][0], ONE));
+    assert_state_approx(&states[1], &c.run_prefix(1).unwrap(), 1e-10);
+    assert_state_approx(&states[2], &c.statevector().unwrap(), 1e-10);
+}
+
+#[test]
+fn test_from_euler() {
+    let scaled = |alpha: f64, m: &Mat<C>| {
+        Mat::from_fn(2, 2, |i, j| cplx(alpha.cos(), alpha.sin()) * m[(i, j)])
+    };
+
+    // Arbitrary single-qubit unitary
+    let u = Gate::from_euler("XYX", (0.3, 1.1, -2.0), 0).unwrap();
+    let u = Gate::new(scaled(0.8, &(Gate::t(0).matrix() * u.matrix())), vec![0]).unwrap();
+    let (alpha, angles) = u.zyz_decompose().unwrap();
+    let rebuilt = Gate::from_euler("ZYZ", angles, 0).unwrap();
+    assert!(mat_approx_eq(&scaled(alpha, rebuilt.matrix()), u.matrix()));
+
+    // Conventions agree on known rotations
+    let quarter = std::f64::consts::FRAC_PI_2;
+    let rx = Gate::from_euler("ZYZ", (-quarter, 0.7, quarter), 0).unwrap();
+    assert!(mat_approx_eq(rx.matrix(), Gate::rx(0.7, 0).matrix()));
+    let zxz = Gate::from_euler("ZXZ", (0.2, 0.0, 0.5), 0).unwrap();
+    assert!(mat_approx_eq(zxz.matrix(), Gate::rz(0.7, 0).matrix()));
+
+    // Diagonal and anti-diagonal gates
+    for g in [Gate::s(0), Gate::x(0), Gate::h(0)] {
+        let (alpha, angles) = g.zyz_decompose().unwrap();
+        let rebuilt = Gate::from_euler("ZYZ", angles, 0).unwrap();
+        assert!(mat_approx_eq(&scaled(alpha, rebuilt.matrix()), g.matrix()));
+    }
+
+    assert!(Gate::from_euler("XYZ", (0.0, 0.0, 0.0), 0).is_none());
+    assert!(Gate::cx(0, 1).unwrap().zyz_decompose().is_none());
+}
+
+#[test]
+fn test_states_equal_up_to_permutation() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.ry(0.4, 1).unwrap();
+    c.cx(0, 2).unwrap();
+    let mut swapped = Circuit::new(3);
+    swapped.h(1).unwrap();
+    swapped.ry(0.4, 0).unwrap();
+    swapped.cx(1, 2).unwrap();
+    swapped.global_phase(0.6).unwrap();
+    let (a, b) = (c.statevector().unwrap(), swapped.statevector().unwrap());
+
+    assert!(states_equal_up_to_permutation(&a, &b, &[1, 0, 2]));
+    assert!(!states_equal_up_to_permutation(&a, &b, &[0, 1, 2]));
+    assert!(states_equal_up_to_permutation(&a, &a, &[0, 1, 2]));
+    assert!(!states_equal_up_to_permutation(&a, &b, &[1, 1, 2]));
+    assert!(!states_equal_up_to_permutation(&a, &b, &[1, 0]));
+}
+
+#[test]
+fn test_measure_some() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.cx(0, 2).unwrap();
+    let mut seen = [false; 2];
+    for seed in 0..20 {
+        let (outcomes, state) = c.measure_some(&[0], seed).unwrap();
+        let bit = outcomes[&0];
+        seen[bit] = true;
+        // Qubits 1 and 2 are now qubits 0 and 1, both equal to the outcome
+        assert_eq!(state.nrows(), 4);
+        let expected = if bit == 1 { 3 } else { 0 };
+        for i in 0..4 {
+            assert!(approx(state[i], if i == expected { ONE } else { Z }));
+        }
+    }
+    assert_eq!(seen, [true, true]);
+
+    let (outcomes, state) = c.measure_some(&[2, 1], 3).unwrap();
+    assert_eq!(outcomes[&1], outcomes[&2]);
+    assert_eq!(state.nrows(), 2);
+    assert!(approx(state[outcomes[&1]], ONE));
+    assert_eq!(
+        c.measure_some(&[3], 0).unwrap_err(),
+        CircuitError::QubitOutOfRange(3)
+    );
+}
+
+#[test]
+fn test_structural_hash() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.rz(0.3, 1).unwrap();
+    assert_eq!(c.structural_hash(), c.clone().structural_hash());
+
+    let mut reordered = Circuit::new(2);
+    reordered.h(0).unwrap();
+    reordered.rz(0.3, 1).unwrap();
+    reordered.cx(0, 1).unwrap();
+    assert_ne!(c.structural_hash(), reordered.structural_hash());
+
+    assert_ne!(
+        Circuit::new(2).structural_hash(),
+        Circuit::new(3).structural_hash()
+    );
+
+    let mut angle = Circuit::new(2);
+    angle.h(0).unwrap();
+    angle.cx(0, 1).unwrap();
+    angle.rz(0.3 + 1e-13, 1).unwrap();
+    assert_eq!(c.structural_hash(), angle.structural_hash());
+}
+
+#[test]
+fn test_hadamard_precision() {
+    // 1/√2 = 0.70710678118654752440084436210484903928...
+    let reference = faer::fx128 {
+        0: std::f64::consts::FRAC_1_SQRT_2,
+        1: -4.833646656726457e-17,
+    };
+    let h = Gate::h(0);
+    for (i, j) in [(0, 0), (0, 1), (1, 0)] {
+        let error = (h.matrix()[(i, j)].re - reference).abs();
+        assert!(error.0 < 1e-30, "{:?}", error);
+        assert_eq!(h.matrix()[(i, j)].im.0, 0.0);
+    }
+    assert!(((h.matrix()[(1, 1)].re + reference).abs()).0 < 1e-30);
+
+    // Rounding to f64 is off by about 5e-17
+    let rounded = cplx(1.0 / 2.0f64.sqrt(), 0.0);
+    assert!((rounded.re - reference).abs().0 > f64::EPSILON / 10.0);
+
+    let t = Gate::t(0).matrix()[(1, 1)];
+    assert!((t.re - reference).abs().0 < 1e-30);
+    assert!((t.im - reference).abs().0 < 1e-30);
+}
+
+#[test]
+fn test_permutation_gates() {
+    assert!(Gate::x(0).is_permutation());
+    assert!(Gate::swap(0, 1).unwrap().is_permutation());
+    assert!(Gate::cnx(vec![1, 2], 0).unwrap().is_permutation());
+    assert!(!Gate::h(0).is_permutation());
+    assert!(!Gate::z(0).is_permutation());
+
+    let mut c = Circuit::new(8);
+    for k in 0..8 {
+        c.x(k).unwrap();
+        c.cx(k, (k + 3) % 8).unwrap();
+        c.swap(k, (k + 5) % 8).unwrap();
+        if k % 3 == 0 {
+            c.h(k).unwrap();
+        }
+    }
+    // The dense strategy multiplies by the expanded matrices instead
+    assert_state_approx(
+        &c.statevector_sparse().unwrap(),
+        &c.statevector().unwrap(),
+        1e-10,
+    );
+}
+
+#[test]
+fn test_controlled_by() {
+    let mut bell = Circuit::new(2);
+    bell.h(0).unwrap();
+    bell.cx(0, 1).unwrap();
+    let controlled = bell.controlled_by(2).unwrap();
+    assert_eq!(controlled.num_qubits(), 3);
+    assert_eq!(controlled.gate_order(), vec!["ch", "ccx"]);
+
+    // Control in |0⟩: nothing happens
+    let res = controlled.run().unwrap();
+    assert!(approx(res["000"], ONE));
+
+    // Control in |1⟩: the Bell pair is prepared
+    let mut c = Circuit::new(3);
+    c.x(2).unwrap();
+    c.append(&controlled).unwrap();
+    let res = c.run().unwrap();
+    let a = cplx(1.0 / 2.0f64.sqrt(), 0.0);
+    assert!(approx(res["100"], a));
+    assert!(approx(res["111"], a));
+
+    // A control past the end grows the register up to it
+    let wide = bell.controlled_by(3).unwrap();
+    assert_eq!(wide.num_qubits(), 4);
+    assert_eq!(wide.gates()[1].qubits(), &[1, 0, 3]);
+
+    // Existing qubits are rejected, even ones no gate acts on
+    assert_eq!(
+        bell.controlled_by(1).unwrap_err(),
+        CircuitError::QubitOutOfRange(1)
+    );
+    let mut idle = Circuit::new(3);
+    idle.h(0).unwrap();
+    assert_eq!(
+        idle.controlled_by(2).unwrap_err(),
+        CircuitError::QubitOutOfRange(2)
+    );
+
+    // Measurements and classical conditions can't be controlled
+    let mut m = Circuit::new(1);
+    m.h(0).unwrap();
+    m.measure(0, 0).unwrap();
+    assert_eq!(
+        m.controlled_by(1).unwrap_err(),
+        CircuitError::ClassicalGate(1)
+    );
+    let mut x = Circuit::new(1);
+    x.x_if(0, 0).unwrap();
+    assert_eq!(
+        x.controlled_by(1).unwrap_err(),
+        CircuitError::ClassicalGate(0)
+    );
+}
+
+#[test]
+fn test_extract_phase_poly() {
+    let mut c = Circuit::new(3);
+    c.t(0).unwrap();
+    c.cz(0, 1).unwrap();
+    c.t(1).unwrap();
+    c.ccz(0, 1, 2).unwrap();
+    c.s(2).unwrap();
+    let poly = c.extract_phase_poly().unwrap();
+    assert_eq!(poly.qubits, 3);
+    assert!(poly.terms.iter().all(|&(mask, _)| mask > 0 && mask < 8));
+    assert!(mat_approx_eq(
+        &poly.to_circuit().unitary().unwrap(),
+        &c.unitary().unwrap()
+    ));
+
+    // A single T is a single term
+    let mut t = Circuit::new(1);
+    t.t(0).unwrap();
+    let poly = t.extract_phase_poly().unwrap();
+    assert_eq!(poly.global, 0.0);
+    assert_eq!(poly.terms.len(), 1);
+    assert_eq!(poly.terms[0].0, 1);
+    assert!((poly.terms[0].1 - std::f64::consts::FRAC_PI_4).abs() < TOLERANCE);
+
+    c.h(0).unwrap();
+    assert!(c.extract_phase_poly().is_none());
+}
+
+#[test]
+fn test_phase_poly_runs() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.t(0).unwrap();
+    c.cz(0, 1).unwrap();
+    c.t(1).unwrap();
+    c.h(1).unwrap();
+    c.s(0).unwrap();
+    let runs = c.phase_poly_runs();
+    let ranges: Vec<_> = runs.iter().map(|(r, _)| r.clone()).collect();
+    assert_eq!(ranges, vec![1..4, 5..6]);
+
+    // Swapping each run for its resynthesis keeps the unitary
+    let mut resynthesised = Circuit::new(2);
+    let mut next = 0;
+    for (range, poly) in &runs {
+        resynthesised
+            .append(&c.with_gates(c.gates()[next..range.start].to_vec()))
+            .unwrap();
+        resynthesised.append(&poly.to_circuit()).unwrap();
+        next = range.end;
+    }
+    resynthesised
+        .append(&c.with_gates(c.gates()[next..].to_vec()))
+        .unwrap();
+    assert!(mat_approx_eq(
+        &resynthesised.unitary().unwrap(),
+        &c.unitary().unwrap()
+    ));
+
+    // Measurements end a run even though they act as the identity
+    let mut m = Circuit::new(1);
+    m.t(0).unwrap();
+    m.measure(0, 0).unwrap();
+    m.t(0).unwrap();
+    assert!(m.extract_phase_poly().is_none());
+    let ranges: Vec<_> = m.phase_poly_runs().into_iter().map(|(r, _)| r).collect();
+    assert_eq!(ranges, vec![0..1, 2..3]);
+
+    // Too wide to hold a phase per basis state
+    let mut wide = Circuit::new(MAX_QUBITS + 1);
+    wide.t(0).unwrap();
+    assert!(wide.extract_phase_poly().is_none());
+    assert!(wide.phase_poly_runs().is_empty());
+}
+
+#[test]
+fn test_uniformly_controlled() {
+    let identity = Gate::new(mat![[ONE, Z], [Z, ONE]], vec![0]).unwrap();
+    let g = Gate::uniformly_controlled(vec![identity.clone(), Gate::x(0)], vec![1], 0).unwrap();
+    let cx = Gate::cx(1, 0).unwrap();
+    assert_eq!(g.qubits(), cx.qubits());
+    assert!(mat_approx_eq(g.matrix(), cx.matrix()));
+
+    assert!(Gate::uniformly_controlled(vec![identity.clone()], vec![1], 0).is_none());
+    assert!(
+        Gate::uniformly_controlled(vec![identity, Gate::swap(0, 1).unwrap()], vec![1], 0).is_none()
+    );
+    assert!(Gate::uniformly_controlled(vec![Gate::x(0), Gate::x(0)], vec![0], 0).is_none());
+}
+
+#[test]
+fn test_prepare_amplitudes() {
+    let a = cplx(1.0 / 2.0f64.sqrt(), 0.0);
+    let c = Circuit::prepare_amplitudes(&[a, Z, Z, a]).unwrap();
+    let res = c.run().unwrap();
+    assert!(approx(res["00"], a));
+    assert!(approx(res["11"], a));
+    assert!(approx(res["01"], Z));
+    assert!(approx(res["10"], Z));
+
+    // Unnormalized amplitudes with phases on three qubits
+    let amplitudes: Vec<C> = (0..8)
+        .map(|k| cplx(k as f64, 1.0 - k as f64 / 2.0))
+        .collect();
+    let total: f64 = amplitudes
+        .iter()
+        .map(|x| norm(*x).powi(2))
+        .sum::<f64>()
+        .sqrt();
+    let state = Circuit::prepare_amplitudes(&amplitudes)
+        .unwrap()
+        .statevector()
+        .unwrap();
+    let scale = cplx(1.0 / total, 0.0);
+    let expected = Col::from_fn(8, |k| amplitudes[k] * scale);
+    assert_state_approx(&state, &expected, TOLERANCE);
+
+    assert_eq!(
+        Circuit::prepare_amplitudes(&[ONE, Z, Z]).unwrap_err(),
+        CircuitError::InvalidDimension(3, 1)
+    );
+    assert_eq!(
+        Circuit::prepare_amplitudes(&[Z, Z]).unwrap_err(),
+        CircuitError::StateCollapsed
+    );
+}
+
+#[test]
+fn test_choi_matrix() {
+    // |Ω⟩ on two qubits is the Bell state (|00⟩ + |11⟩)/√2
+    let choi = Circuit::new(1).choi_matrix().unwrap();
+    let half = cplx(0.5, 0.0);
+    let expected = mat![
+        [half, Z, Z, half],
+        [Z, Z, Z, Z],
+        [Z, Z, Z, Z],
+        [half, Z, Z, half],
+    ];
+    assert!(mat_approx_eq(&choi, &expected));
+
+    // X on the upper half sends the pair to (|10⟩ + |01⟩)/√2
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    let choi = c.choi_matrix().unwrap();
+    assert!(approx(choi[(1, 2)], half));
+    assert!(approx(choi[(0, 0)], Z));
+    assert!(approx(trace(&choi), ONE));
+}
+
+#[test]
+fn test_basis_state_tracking() {
+    let n = 8;
+    let mut c = Circuit::new(n);
+    for i in 0..200 {
+        c.x(i % n).unwrap();
+        c.cx(i % n, (i * 7 + 3) % n).unwrap();
+        if i % 13 == 0 {
+            c.s((i + 1) % n).unwrap();
+        }
+    }
+    let expected = c.statevector_sparse().unwrap();
+    // The default dense strategy would expand every gate it applies
+    EXPANSIONS.with(|count| count.set(0));
+    let state = c.statevector().unwrap();
+    assert_eq!(EXPANSIONS.with(|count| count.get()), 0);
+    assert_state_approx(&state, &expected, TOLERANCE);
+    assert_eq!(c.run_from_bits("00000101").unwrap(), {
+        let mut prefixed = Circuit::new(n);
+        prefixed.x(0).unwrap();
+        prefixed.x(2).unwrap();
+        prefixed.append(&c).unwrap();
+        prefixed.run().unwrap()
+    });
+
+    // A superposition switches back to the statevector for the rest
+    c.h(4).unwrap();
+    c.cx(4, 5).unwrap();
+    c.t(5).unwrap();
+    let expected = c.statevector_sparse().unwrap();
+    EXPANSIONS.with(|count| count.set(0));
+    let state = c.statevector().unwrap();
+    assert_eq!(EXPANSIONS.with(|count| count.get()), 3);
+    assert_state_approx(&state, &expected, TOLERANCE);
+}
+
+#[test]
+fn test_gate_labels() {
+    let m = Gate::from_euler("ZYZ", (0.3, 1.1, -0.2), 0)
+        .unwrap()
+        .matrix()
+        .clone();
+    let mut c = Circuit::new(2);
+    c.h(1).unwrap();
+    c.add_gate(
+        Gate::new(m, vec![0])
+            .unwrap()
+            .with_label("prep".to_string()),
+    )
+    .unwrap();
+    assert_eq!(c.gates()[1].label(), Some("prep"));
+    assert_eq!(c.gates()[1].name(), "unitary");
+    let drawing = c.draw();
+    assert!(drawing.starts_with("q0: ─prep─"));
+    assert!(!drawing.contains("UNITARY"));
+
+    let src = c.to_qasm().unwrap();
+    assert!(src.contains("h q[1];\n// prep\nU("));
+    assert!(Circuit::from_qasm(&src).unwrap().equiv(&c));
+
+    // Labels are kept by controlled versions of the gate
+    let g = Gate::x(0).with_label("flip".to_string());
+    assert_eq!(g.controlled(vec![1]).unwrap().label(), Some("flip"));
+}
+
+#[test]
+fn test_bit_reverse() {
+    assert_eq!(bit_reverse(1, 3), 4);
+    assert_eq!(bit_reverse(0b110, 3), 0b011);
+    assert_eq!(bit_reverse(0b1011, 4), 0b1101);
+
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 2).unwrap();
+    c.t(2).unwrap();
+    c.ry(0.4, 1).unwrap();
+    let state = c.statevector().unwrap();
+    let reversed = reverse_statevector(&state, 3);
+    assert!(approx(reversed[0b100], state[0b001]));
+    assert_state_approx(&reverse_statevector(&reversed, 3), &state, TOLERANCE);
+
+    // The QFT without its swaps outputs the bit-reversed QFT
+    let mut input = Circuit::new(3);
+    input.x(0).unwrap();
+    input.h(2).unwrap();
+    let qft = (input.clone() + Circuit::qft(3)).statevector().unwrap();
+    let no_swap = (input + Circuit::qft_no_swap(3)).statevector().unwrap();
+    assert_state_approx(&reverse_statevector(&no_swap, 3), &qft, TOLERANCE);
+}
+
+#[test]
+fn test_swap_network() {
+    let order = [2, 0, 3, 1];
+    let mut c = Circuit::new(4);
+    // Qubit q starts in |bits[q]⟩
+    let bits = [1, 1, 0, 0];
+    for (q, b) in bits.iter().enumerate() {
+        if *b == 1 {
+            c.x(q).unwrap();
+        }
+    }
+    let prep = c.gates().len();
+    c.swap_network(&order).unwrap();
+    // Three inversions: (2, 0), (2, 1) and (3, 1)
+    assert_eq!(c.gates().len() - prep, 3);
+    assert!(
+        c.gates()[prep..]
+            .iter()
+            .all(|g| g.name() == "swap" && g.qubits()[0].abs_diff(g.qubits()[1]) == 1)
+    );
+
+    // Qubit i now holds bits[order[i]], labels have qubit 0 rightmost
+    let label: String = order.iter().rev().map(|q| bits[*q].to_string()).collect();
+    assert_eq!(label, "1010");
+    assert!(approx(c.run().unwrap()[&label], ONE));
+
+    assert_eq!(
+        c.swap_network(&[0, 0, 1, 2]).unwrap_err(),
+        CircuitError::InvalidPermutation(vec![0, 0, 1, 2])
+    );
+    assert!(c.swap_network(&[0, 1, 2]).is_err());
+}
+
+#[test]
+fn test_unitary_size_guard() {
+    let mut c = Circuit::new(20);
+    c.h(0).unwrap();
+    assert_eq!(c.unitary().unwrap_err(), CircuitError::TooManyQubits(20));
+    assert_eq!(
+        c.choi_matrix().unwrap_err(),
+        CircuitError::TooManyQubits(20)
+    );
+    assert!(!c.is_unitary());
+    assert!(!c.equiv(&c));
+
+    let c = Circuit::new(MAX_QUBITS / 2 + 1);
+    assert!(c.unitary().is_err());
+}
+
+#[test]
+fn test_amplitudes_iter() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    let total: f64 = c
+        .amplitudes_iter()
+        .unwrap()
+        .map(|(_, a)| norm(a).powi(2))
+        .sum();
+    assert!((total - 1.0).abs() < TOLERANCE);
+
+    let indices: Vec<usize> = c.amplitudes_iter().unwrap().map(|(i, _)| i).collect();
+    assert_eq!(indices, [0, 1, 2, 3]);
+    let first = c
+        .amplitudes_iter()
+        .unwrap()
+        .find(|(_, a)| norm(*a) > TOLERANCE);
+    assert_eq!(first.map(|(i, _)| i), Some(0));
+}
+
+#[test]
+fn test_set_tolerance() {
+    // Scales every amplitude by 1 + 1e-4, as accumulated rounding errors would
+    let drift = cplx(1.0 + 1E-4, 0.0);
+    let mut c = Circuit::new(1);
+    c.h(0).unwrap();
+    c.add_gate(Gate::new_unchecked(mat![[drift, Z], [Z, drift]], vec![0]))
+        .unwrap();
+    assert_eq!(c.tolerance(), TOLERANCE);
+    assert_eq!(c.run_checked().unwrap_err(), CircuitError::NonUnitary);
+    assert!(!c.is_unitary());
+
+    c.set_tolerance(1E-3);
+    let res = c.run_checked().unwrap();
+    assert!(approx_eq(
+        res["1"],
+        cplx((1.0 + 1E-4) / 2.0f64.sqrt(), 0.0),
+        1E-10
+    ));
+    assert!(c.is_unitary());
+
+    // Measuring |1⟩ has probability sin²(0.001) ≈ 1e-6, below the default
+    let mut c = Circuit::new(1);
+    c.ry(0.002, 0).unwrap();
+    assert_eq!(
+        c.measure_and_project(0, 1).unwrap_err(),
+        CircuitError::InvalidOutcome(1)
+    );
+    c.set_tolerance(1E-8);
+    let (p, state) = c.measure_and_project(0, 1).unwrap();
+    assert!((p - 0.001f64.sin().powi(2)).abs() < 1E-12);
+    assert!(approx(state[1], ONE));
+}
+
+#[test]
+fn test_fuse_across_commuting_gates() {
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.x(1).unwrap();
+    c.h(0).unwrap();
+    let fused = c.fuse();
+    assert_eq!(fused.gate_order(), vec!["fused", "x"]);
+    assert!(is_identity(fused.gates()[0].matrix()));
+    assert_eq!(fused.gates()[1].qubits(), &[1]);
+    assert!(c.equiv(&fused));
+
+    // S commutes with a CZ on the same qubit, X doesn't
+    let mut c = Circuit::new(2);
+    c.s(0).unwrap();
+    c.cz(0, 1).unwrap();
+    c.s(0).unwrap();
+    c.x(0).unwrap();
+    c.cz(1, 0).unwrap();
+    c.x(0).unwrap();
+    let fused = c.fuse();
+    assert_eq!(fused.gate_order(), vec!["fused", "cz", "x", "cz", "x"]);
+    assert!(c.equiv(&fused));
+}
+
+#[test]
+fn test_amplitude_fast() {
+    let mut c = Circuit::new(3);
+    c.s(0).unwrap();
+    c.t(1).unwrap();
+    c.cz(0, 2).unwrap();
+    c.rz(0.7, 2).unwrap();
+    c.cp(1.3, 1, 2).unwrap();
+    c.global_phase(-0.4).unwrap();
+    for bits in ["000", "101", "111"] {
+        let fast = c.amplitude_fast(bits).unwrap();
+        assert!(approx(fast, c.amplitude_str(bits).unwrap()));
+    }
+    // Only Rz(0.7) and the global phase act on |000⟩
+    let phase = -0.35 - 0.4f64;
+    assert!(approx(
+        c.amplitude_fast("000").unwrap(),
+        cplx(phase.cos(), phase.sin())
+    ));
+    assert_eq!(c.amplitude_fast("00"), None);
+
+    c.h(1).unwrap();
+    assert_eq!(c.amplitude_fast("000"), None);
+
+    // A measurement is the identity but still can't be skipped over
+    let mut m = Circuit::new(1);
+    m.t(0).unwrap();
+    m.measure(0, 0).unwrap();
+    assert_eq!(m.amplitude_fast("0"), None);
+    assert_eq!(
+        m.amplitude_str("0").unwrap_err(),
+        CircuitError::ClassicalGate(1)
+    );
+}
+
+#[test]
+fn test_from_boolean() {
+    let and = Circuit::from_boolean(2, 1, |x| vec![x[0] && x[1]]).unwrap();
+    assert_eq!(and.num_qubits(), 3);
+    assert_eq!(and.gate_order(), vec!["perm"]);
+    // Labels are y x1 x0
+    for (input, output) in [
+        ("000", "000"),
+        ("001", "001"),
+        ("010", "010"),
+        ("011", "111"),
+        ("100", "100"),
+        ("101", "101"),
+        ("110", "110"),
+        ("111", "011"),
+    ] {
+        let res = and.run_from_bits(input).unwrap();
+        assert!(approx(res[output], ONE), "{} -> {}", input, output);
+    }
+
+    assert_eq!(
+        Circuit::from_boolean(2, 1, |_| vec![true, false]).unwrap_err(),
+        CircuitError::InvalidBits("10".to_string())
+    );
+}
+
+#[test]
+fn test_classical_bits() {
+    let mut c = Circuit::new(2);
+    c.x_if(1, 0).unwrap();
+    c.measure(0, 0).unwrap();
+    assert_eq!(
+        c.validate().unwrap_err(),
+        CircuitError::UndefinedClassicalBit(0)
+    );
+    assert_eq!(
+        c.run_shot(0).unwrap_err(),
+        CircuitError::UndefinedClassicalBit(0)
+    );
+
+    // Teleport-like correction: copy the measured value of qubit 0 to qubit 1
+    let mut c = Circuit::new(2);
+    c.h(0).unwrap();
+    c.measure(0, 0).unwrap();
+    c.x_if(1, 0).unwrap();
+    c.validate().unwrap();
+    assert!(c.gates()[1].is_classical());
+    for seed in 0..8 {
+        let (state, bits) = c.run_shot(seed).unwrap();
+        let index = if bits[0] { 0b11 } else { 0b00 };
+        assert!(approx(state[index], ONE));
+    }
+    let outcomes: Vec<bool> = (0..16).map(|seed| c.run_shot(seed).unwrap().1[0]).collect();
+    assert!(outcomes.contains(&true) && outcomes.contains(&false));
+
+    // Unitary simulators refuse classical gates
+    assert_eq!(c.run().unwrap_err(), CircuitError::ClassicalGate(1));
+    assert_eq!(c.to_qasm().unwrap_err(), CircuitError::ClassicalGate(1));
+    assert_eq!(c.fuse().gates().len(), 3);
+}
+
+#[test]
+fn test_run_from_bits_unbound() {
+    let mut c = Circuit::new(2);
+    c.x(0).unwrap();
+    c.ry(Parameter::Param("theta".to_string()), 1).unwrap();
+    assert_eq!(
+        c.run_from_bits("01").unwrap_err(),
+        CircuitError::UnboundParameter("theta".to_string())
+    );
+}
+
+#[test]
+fn test_unitary_unbound() {
+    let mut c = Circuit::new(1);
+    c.rz(Parameter::Param("theta".to_string()), 0).unwrap();
+    assert_eq!(
+        c.unitary().unwrap_err(),
+        CircuitError::UnboundParameter("theta".to_string())
+    );
+    assert!(!c.is_unitary());
+    assert!(!c.equiv(&Circuit::new(1)));
+}
@@ -0,0 +1,63 @@
+//! Dependency-free JSON export of simulation results, with keys sorted so the
+//! output is stable between runs.
+
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod tests;
+
+use crate::core::Scalar;
+
+fn quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            c if (c as u32) < 0x20 => res += &format!("\\u{:04x}", c as u32),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Writes `(key, value)` pairs as an indented JSON object, sorted by key.
+fn object(mut entries: Vec<(&String, String)>) -> String {
+    if entries.is_empty() {
+        return "{}".to_string();
+    }
+    entries.sort();
+    let body = entries
+        .into_iter()
+        .map(|(k, v)| format!("  {}: {}", quote(k), v))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("{{\n{}\n}}", body)
+}
+
+/// Formats measurement counts as a JSON object mapping bitstrings to counts.
+pub fn results_to_json(counts: &HashMap<String, usize>) -> String {
+    object(counts.iter().map(|(k, n)| (k, n.to_string())).collect())
+}
+
+/// Formats a float as a JSON number, or `null` for NaN and infinities which
+/// JSON can't represent.
+fn number(x: f64) -> String {
+    if x.is_finite() {
+        format!("{:?}", x)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Formats amplitudes as a JSON object mapping bitstrings to `[re, im]`
+/// pairs. Non-finite components are written as `null`.
+pub fn amplitudes_to_json<S: Scalar>(amplitudes: &HashMap<String, S>) -> String {
+    object(
+        amplitudes
+            .iter()
+            .map(|(k, x)| (k, format!("[{}, {}]", number(x.real()), number(x.imag()))))
+            .collect(),
+    )
+}
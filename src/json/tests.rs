@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use crate::core::{C, Circuit, Scalar};
+use crate::json::{amplitudes_to_json, results_to_json};
+
+#[test]
+fn test_results_to_json_sorted() {
+    let counts = HashMap::from([("11".to_string(), 48), ("00".to_string(), 52)]);
+    assert_eq!(
+        results_to_json(&counts),
+        "{\n  \"00\": 52,\n  \"11\": 48\n}"
+    );
+    assert_eq!(results_to_json(&HashMap::new()), "{}");
+}
+
+#[test]
+fn test_amplitudes_to_json() {
+    let mut c = Circuit::new(1);
+    c.x(0).unwrap();
+    let json = amplitudes_to_json(&c.run().unwrap());
+    assert_eq!(json, "{\n  \"0\": [0.0, 0.0],\n  \"1\": [1.0, 0.0]\n}");
+}
+
+#[test]
+fn test_amplitudes_to_json_non_finite() {
+    let amplitudes = HashMap::from([
+        ("0".to_string(), C::from_parts(f64::NAN, 0.5)),
+        (
+            "1".to_string(),
+            C::from_parts(f64::INFINITY, f64::NEG_INFINITY),
+        ),
+    ]);
+    assert_eq!(
+        amplitudes_to_json(&amplitudes),
+        "{\n  \"0\": [null, 0.5],\n  \"1\": [null, null]\n}"
+    );
+}
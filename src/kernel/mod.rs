@@ -0,0 +1,338 @@
+//! Scalar types and the gate application math shared by the simulators.
+//!
+//! This module only needs `alloc`, so it is the part of the crate that still
+//! builds when the `std` feature is disabled.
+
+#[cfg(test)]
+mod tests;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Add, AddAssign, Mul, MulAssign, Neg, Sub};
+
+use faer::traits::ComplexField;
+use faer::{ColMut, Mat, MatRef, c32, c64, cx128, fx128};
+
+pub type C = cx128;
+
+pub const fn cplx(re: f64, im: f64) -> C {
+    C::new(fx128::from_f64(re), fx128::from_f64(im))
+}
+
+/// `1/√2` to the full precision of `fx128`, which `cplx(1.0 / 2.0f64.sqrt(),
+/// 0.0)` would round to the nearest `f64`.
+pub const fn inv_sqrt2() -> C {
+    C::new(fx128::from_f64(0.5).const_sqrt(), fx128::ZERO)
+}
+
+/// Complex scalar type amplitudes and gate matrices can be stored in.
+///
+/// `C` (double-double precision) is the default everywhere; `c64` and `c32`
+/// trade precision for speed and memory on large circuits.
+pub trait Scalar:
+    ComplexField
+    + Copy
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + MulAssign
+{
+    fn from_parts(re: f64, im: f64) -> Self;
+
+    fn from_c(x: C) -> Self;
+
+    fn real(self) -> f64;
+
+    fn imag(self) -> f64;
+
+    fn modulus(self) -> f64;
+}
+
+impl Scalar for C {
+    fn from_parts(re: f64, im: f64) -> Self {
+        cplx(re, im)
+    }
+
+    fn from_c(x: C) -> Self {
+        x
+    }
+
+    fn real(self) -> f64 {
+        self.re.0
+    }
+
+    fn imag(self) -> f64 {
+        self.im.0
+    }
+
+    fn modulus(self) -> f64 {
+        (self.re * self.re + self.im * self.im).sqrt().0
+    }
+}
+
+impl Scalar for c64 {
+    fn from_parts(re: f64, im: f64) -> Self {
+        c64::new(re, im)
+    }
+
+    fn from_c(x: C) -> Self {
+        c64::new(x.re.0, x.im.0)
+    }
+
+    fn real(self) -> f64 {
+        self.re
+    }
+
+    fn imag(self) -> f64 {
+        self.im
+    }
+
+    fn modulus(self) -> f64 {
+        Self::abs_impl(&self)
+    }
+}
+
+impl Scalar for c32 {
+    fn from_parts(re: f64, im: f64) -> Self {
+        c32::new(re as f32, im as f32)
+    }
+
+    fn from_c(x: C) -> Self {
+        c32::new(x.re.0 as f32, x.im.0 as f32)
+    }
+
+    fn real(self) -> f64 {
+        self.re as f64
+    }
+
+    fn imag(self) -> f64 {
+        self.im as f64
+    }
+
+    fn modulus(self) -> f64 {
+        Self::abs_impl(&self) as f64
+    }
+}
+
+/// Applies `mat`, acting on `targets` with the last `controls` of them used
+/// as controls, in place to a statevector.
+///
+/// Diagonal matrices just scale each amplitude, and permutation matrices
+/// (e.g. X, CX or SWAP) just move amplitudes around. Otherwise, only the
+/// amplitudes where every control is set are touched, and they are updated in
+/// groups of `2^k` using the `2^k x 2^k` block acting on the `k` non-control
+/// qubits.
+pub fn apply<S: Scalar>(
+    mat: MatRef<'_, S>,
+    targets: &[usize],
+    controls: usize,
+    state: ColMut<'_, S>,
+) {
+    if is_diagonal(mat) {
+        apply_diagonal(mat, targets, state, 0);
+    } else if let Some(perm) = permutation(mat) {
+        apply_permutation(&perm, targets, state);
+    } else {
+        apply_blocks(mat, targets, controls, state);
+    }
+}
+
+/// Returns `perm` such that `mat` sends basis state `j` to `perm[j]`, if
+/// every column is a basis vector with an entry of exactly one.
+pub fn permutation<S: Scalar>(mat: MatRef<'_, S>) -> Option<Vec<usize>> {
+    let (zero, one) = (S::from_parts(0.0, 0.0), S::from_parts(1.0, 0.0));
+    let n = mat.nrows();
+    (0..n)
+        .map(|j| {
+            let i = (0..n).find(|i| mat[(*i, j)] != zero)?;
+            let rest_zero = (i + 1..n).all(|k| mat[(k, j)] == zero);
+            (mat[(i, j)] == one && rest_zero).then_some(i)
+        })
+        .collect()
+}
+
+/// Moves the amplitude of each basis state `j` of the targets to `perm[j]`.
+fn apply_permutation<S: Scalar>(perm: &[usize], targets: &[usize], mut state: ColMut<'_, S>) {
+    let target_mask = targets.iter().fold(0, |acc, q| acc | (1 << q));
+    let indices: Vec<usize> = (0..perm.len())
+        .map(|l| {
+            targets
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| (l >> bit) & 1 == 1)
+                .fold(0, |acc, (_, q)| acc | (1 << q))
+        })
+        .collect();
+    let mut old = vec![S::from_parts(0.0, 0.0); perm.len()];
+    for base in 0..state.nrows() {
+        if base & target_mask != 0 {
+            continue;
+        }
+        for (l, idx) in indices.iter().enumerate() {
+            old[l] = state[base | idx];
+        }
+        for (l, x) in old.iter().enumerate() {
+            state[base | indices[perm[l]]] = *x;
+        }
+    }
+}
+
+/// Checks that every off-diagonal entry is exactly zero, so the fast path
+/// gives the same result as the general one.
+fn is_diagonal<S: Scalar>(mat: MatRef<'_, S>) -> bool {
+    let zero = S::from_parts(0.0, 0.0);
+    let n = mat.nrows();
+    (0..n).all(|j| (0..n).all(|i| i == j || mat[(i, j)] == zero))
+}
+
+/// Scales each amplitude of `state`, which starts at index `offset` of the
+/// whole statevector, by the matching diagonal entry of `mat`.
+fn apply_diagonal<S: Scalar>(
+    mat: MatRef<'_, S>,
+    targets: &[usize],
+    mut state: ColMut<'_, S>,
+    offset: usize,
+) {
+    let diagonal: Vec<S> = (0..mat.nrows()).map(|k| mat[(k, k)]).collect();
+    for i in 0..state.nrows() {
+        let k = targets
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (bit, q)| acc | ((((offset + i) >> q) & 1) << bit));
+        state[i] *= diagonal[k];
+    }
+}
+
+fn apply_blocks<S: Scalar>(
+    mat: MatRef<'_, S>,
+    targets: &[usize],
+    controls: usize,
+    mut state: ColMut<'_, S>,
+) {
+    let (targets, controls) = targets.split_at(targets.len() - controls);
+    let power = 2usize.pow(targets.len() as u32);
+    let offset = mat.nrows() - power;
+    let target_mask = targets.iter().fold(0, |acc, q| acc | (1 << q));
+    let control_mask = controls.iter().fold(0, |acc, q| acc | (1 << q));
+    let indices: Vec<usize> = (0..power)
+        .map(|l| {
+            targets
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| (l >> bit) & 1 == 1)
+                .fold(0, |acc, (_, q)| acc | (1 << q))
+        })
+        .collect();
+    let zero = S::from_parts(0.0, 0.0);
+    let mut old = vec![zero; power];
+    for base in 0..state.nrows() {
+        if base & target_mask != 0 || base & control_mask != control_mask {
+            continue;
+        }
+        for (l, idx) in indices.iter().enumerate() {
+            old[l] = state[base | idx];
+        }
+        for (row, idx) in indices.iter().enumerate() {
+            let mut acc = zero;
+            for (col, x) in old.iter().enumerate() {
+                acc += mat[(offset + row, offset + col)] * *x;
+            }
+            state[base | idx] = acc;
+        }
+    }
+}
+
+/// Checks whether `apply_chunk` can apply `mat` to chunks of `2^chunk_bits`
+/// amplitudes, i.e. `mat` is diagonal or none of its non-control targets
+/// lies outside of a chunk.
+pub fn is_chunk_local<S: Scalar>(
+    mat: MatRef<'_, S>,
+    targets: &[usize],
+    controls: usize,
+    chunk_bits: usize,
+) -> bool {
+    is_diagonal(mat)
+        || targets[..targets.len() - controls]
+            .iter()
+            .all(|q| *q < chunk_bits)
+}
+
+/// Same as `apply`, restricted to the amplitudes `offset..offset + len` of
+/// the statevector held in `chunk`, where `len` is a power of two and
+/// `offset` a multiple of it.
+///
+/// `is_chunk_local` must hold. Controls outside of the chunk have the same
+/// value for every amplitude in it, so the chunk is either left unchanged or
+/// updated as if those controls weren't there.
+pub fn apply_chunk<S: Scalar>(
+    mat: MatRef<'_, S>,
+    targets: &[usize],
+    controls: usize,
+    chunk: ColMut<'_, S>,
+    offset: usize,
+) {
+    if is_diagonal(mat) {
+        apply_diagonal(mat, targets, chunk, offset);
+        return;
+    }
+    let chunk_bits = chunk.nrows().trailing_zeros() as usize;
+    let (base, controls) = targets.split_at(targets.len() - controls);
+    if controls
+        .iter()
+        .any(|q| *q >= chunk_bits && (offset >> q) & 1 == 0)
+    {
+        return;
+    }
+    let inner: Vec<usize> = controls
+        .iter()
+        .copied()
+        .filter(|q| *q < chunk_bits)
+        .collect();
+    let targets: Vec<usize> = base.iter().chain(&inner).copied().collect();
+    // Only the bottom-right block of a controlled matrix is ever read, so
+    // dropping controls doesn't require shrinking `mat`
+    apply_blocks(mat, &targets, inner.len(), chunk);
+}
+
+/// Expands `mat`, acting on `targets`, to the `2^n x 2^n` matrix acting on
+/// the whole register.
+pub fn expand<S: Scalar>(mat: MatRef<'_, S>, targets: &[usize], n: usize) -> Mat<S> {
+    let power = 2usize.pow(n as u32);
+    let mut big = Mat::zeros(power, power);
+
+    for row in 0..power {
+        for col in 0..power {
+            // Check if non-target qubits are identical between row and col
+            let mut non_target_bits_match = true;
+            for bit_pos in 0..n {
+                if !targets.contains(&bit_pos) {
+                    let row_bit = (row >> bit_pos) & 1;
+                    let col_bit = (col >> bit_pos) & 1;
+                    if row_bit != col_bit {
+                        non_target_bits_match = false;
+                        break;
+                    }
+                }
+            }
+
+            if !non_target_bits_match {
+                continue; // stays 0
+            }
+            let mut small_row = 0;
+            let mut small_col = 0;
+            for (i, target_idx) in targets.iter().enumerate() {
+                if (row >> target_idx) & 1 == 1 {
+                    small_row |= 1 << i;
+                }
+                if (col >> target_idx) & 1 == 1 {
+                    small_col |= 1 << i;
+                }
+            }
+
+            big[(row, col)] = mat[(small_row, small_col)];
+        }
+    }
+    big
+}
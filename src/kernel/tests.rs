@@ -0,0 +1,110 @@
+use faer::{Col, mat};
+
+use crate::kernel::{
+    C, Scalar, apply, apply_blocks, apply_chunk, cplx, expand, is_chunk_local, is_diagonal,
+    permutation,
+};
+
+#[test]
+fn test_apply_matches_expand() {
+    let (o, z) = (cplx(1.0, 0.0), cplx(0.0, 0.0));
+    let x = mat![[z, o], [o, z]];
+    // CX with qubit 2 as control and qubit 0 as target, on 3 qubits
+    let cx = mat![[o, z, z, z], [z, o, z, z], [z, z, z, o], [z, z, o, z],];
+    let state: Col<C> = Col::from_fn(8, |i| cplx(i as f64, 1.0 - i as f64));
+    for (m, targets, controls) in [(x.as_ref(), &[1][..], 0), (cx.as_ref(), &[0, 2][..], 1)] {
+        let mut sparse = state.clone();
+        apply(m, targets, controls, sparse.as_mut());
+        let dense = expand(m, targets, 3) * &state;
+        for i in 0..8 {
+            assert!((sparse[i] - dense[i]).modulus() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn test_diagonal_fast_path() {
+    let (o, z) = (cplx(1.0, 0.0), cplx(0.0, 0.0));
+    let phase = |t: f64| cplx(t.cos(), t.sin());
+    let x = mat![[z, o], [o, z]];
+    assert!(!is_diagonal(x.as_ref()));
+    // Controlled phases and single-qubit phases, as in a QFT, on 4 qubits
+    let mut gates = Vec::new();
+    for k in 1..6 {
+        let t = 0.37 * k as f64;
+        let cp = mat![
+            [o, z, z, z],
+            [z, o, z, z],
+            [z, z, o, z],
+            [z, z, z, phase(t)],
+        ];
+        gates.push((cp, vec![k % 4, (k + 1) % 4], 1));
+        let p = mat![[o, z], [z, phase(-t)]];
+        gates.push((p, vec![(k + 2) % 4], 0));
+    }
+    let start: Col<C> = Col::from_fn(16, |i| cplx(0.25, 0.01 * i as f64));
+    let (mut fast, mut general) = (start.clone(), start);
+    for (m, targets, controls) in &gates {
+        assert!(is_diagonal(m.as_ref()));
+        apply(m.as_ref(), targets, *controls, fast.as_mut());
+        apply_blocks(m.as_ref(), targets, *controls, general.as_mut());
+    }
+    for i in 0..16 {
+        assert!((fast[i] - general[i]).modulus() < 1e-12);
+    }
+}
+
+#[test]
+fn test_apply_chunk() {
+    let (o, z) = (cplx(1.0, 0.0), cplx(0.0, 0.0));
+    let x = mat![[z, o], [o, z]];
+    let cx = mat![[o, z, z, z], [z, o, z, z], [z, z, z, o], [z, z, o, z],];
+    let cz = mat![[o, z, z, z], [z, o, z, z], [z, z, o, z], [z, z, z, -o],];
+    let state: Col<C> = Col::from_fn(16, |i| cplx(i as f64, 1.0 - i as f64));
+    // X on 1, CX from 3 onto 0, CZ between 0 and 3, with chunks of 4
+    for (m, targets, controls) in [
+        (x.as_ref(), &[1][..], 0),
+        (cx.as_ref(), &[0, 3][..], 1),
+        (cz.as_ref(), &[3, 0][..], 1),
+    ] {
+        assert!(is_chunk_local(m, targets, controls, 2));
+        let mut whole = state.clone();
+        apply(m, targets, controls, whole.as_mut());
+        let mut chunked = state.clone();
+        for offset in (0..16).step_by(4) {
+            let chunk = chunked.as_mut().subrows_mut(offset, 4);
+            apply_chunk(m, targets, controls, chunk, offset);
+        }
+        for i in 0..16 {
+            assert!((whole[i] - chunked[i]).modulus() < 1e-12);
+        }
+    }
+    assert!(!is_chunk_local(x.as_ref(), &[2], 0, 2));
+    assert!(!is_chunk_local(cx.as_ref(), &[3, 0], 1, 2));
+}
+
+#[test]
+fn test_permutation_fast_path() {
+    let (o, z) = (cplx(1.0, 0.0), cplx(0.0, 0.0));
+    let x = mat![[z, o], [o, z]];
+    let cx = mat![[o, z, z, z], [z, o, z, z], [z, z, z, o], [z, z, o, z],];
+    let swap = mat![[o, z, z, z], [z, z, o, z], [z, o, z, z], [z, z, z, o],];
+    assert_eq!(permutation(swap.as_ref()), Some(vec![0, 2, 1, 3]));
+    assert_eq!(permutation(mat![[o, z], [z, -o]].as_ref()), None);
+
+    let mut gates = Vec::new();
+    for k in 0..8 {
+        gates.push((x.as_ref(), vec![k], 0));
+        gates.push((cx.as_ref(), vec![(k + 3) % 8, k], 1));
+        gates.push((swap.as_ref(), vec![k, (k + 5) % 8], 0));
+    }
+    let start: Col<C> = Col::from_fn(256, |i| cplx(i as f64, 0.5 * i as f64));
+    let (mut fast, mut general) = (start.clone(), start);
+    for (m, targets, controls) in &gates {
+        apply(*m, targets, *controls, fast.as_mut());
+        apply_blocks(*m, targets, *controls, general.as_mut());
+    }
+    for i in 0..256 {
+        assert!((fast[i] - general[i]).modulus() < 1e-12);
+    }
+}
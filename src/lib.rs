@@ -0,0 +1,45 @@
+//! Quantum circuit simulation on dense and sparse statevectors, with a
+//! stabilizer backend for Clifford circuits.
+//!
+//! Without the default `std` feature only the `kernel` module is built, for
+//! running small simulations on embedded targets:
+//!
+//! ```sh
+//! cargo build --lib --no-default-features
+//! ```
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod core;
+#[cfg(feature = "std")]
+pub mod json;
+pub mod kernel;
+#[cfg(feature = "std")]
+pub mod noise;
+#[cfg(feature = "std")]
+pub mod npy;
+#[cfg(feature = "std")]
+pub mod observables;
+#[cfg(feature = "std")]
+pub mod qasm;
+#[cfg(feature = "std")]
+pub mod stabilizer;
+
+pub use faer::{Col, Mat};
+
+pub use crate::kernel::C;
+
+/// Everything needed to build and run circuits, including custom gates,
+/// without depending on `faer` directly.
+#[cfg(feature = "std")]
+pub mod prelude {
+    pub use faer::{Col, Mat, mat};
+
+    pub use crate::core::{
+        C, Circuit, CircuitError, Gate, IM, ONE, Parameter, Scalar, TOLERANCE, Z, approx_eq, cplx,
+        norm,
+    };
+}
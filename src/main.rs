@@ -1,8 +1,6 @@
-use crate::core::{Circuit, Gate, display_result};
+use rustomic::core::{Circuit, CircuitError, Gate, display_result};
 
-pub mod core;
-
-pub fn test_circuit() -> Result<(), ()> {
+pub fn test_circuit() -> Result<(), CircuitError> {
     let mut c = Circuit::new(3);
     c.h(0)?;
     c.x(1)?;
@@ -13,7 +11,7 @@ pub fn test_circuit() -> Result<(), ()> {
     Ok(())
 }
 
-fn main() -> Result<(), ()> {
+fn main() -> Result<(), CircuitError> {
     let mut c = Circuit::new(3);
     c.h(0)?;
     c.x(1)?;
@@ -24,7 +22,11 @@ fn main() -> Result<(), ()> {
     println!("--------------------");
     let mut c = Circuit::new(2);
     c.h(1)?;
-    c.add_gate(Gate::h(0).controlled(vec![1]).ok_or(())?)?;
+    c.add_gate(
+        Gate::h(0)
+            .controlled(vec![1])
+            .ok_or(CircuitError::InvalidGate)?,
+    )?;
     display_result(&c.run()?);
     println!("--------------------");
     let mut c = Circuit::new(2);
@@ -0,0 +1,37 @@
+//! Noise models for Monte Carlo trajectory simulation, see
+//! `Circuit::run_trajectory`.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+
+/// Depolarizing noise applied after each gate: every qubit the gate touches
+/// independently gets a uniformly random X, Y or Z error with the gate's
+/// error probability.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NoiseModel {
+    default: f64,
+    gates: HashMap<String, f64>,
+}
+
+impl NoiseModel {
+    /// Creates a model where every gate has error probability `p`.
+    pub fn new(p: f64) -> Self {
+        Self {
+            default: p,
+            gates: HashMap::new(),
+        }
+    }
+
+    /// Overrides the error probability of the gates named `name`.
+    pub fn with_gate(mut self, name: &str, p: f64) -> Self {
+        self.gates.insert(name.to_string(), p);
+        self
+    }
+
+    /// Returns the error probability after a gate named `name`.
+    pub fn probability(&self, name: &str) -> f64 {
+        self.gates.get(name).copied().unwrap_or(self.default)
+    }
+}
@@ -0,0 +1,9 @@
+use crate::noise::NoiseModel;
+
+#[test]
+fn test_probability() {
+    let noise = NoiseModel::new(0.01).with_gate("cx", 0.05);
+    assert_eq!(noise.probability("h"), 0.01);
+    assert_eq!(noise.probability("cx"), 0.05);
+    assert_eq!(NoiseModel::default().probability("x"), 0.0);
+}
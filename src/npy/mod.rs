@@ -0,0 +1,133 @@
+//! Minimal reader and writer for NumPy's `.npy` format, restricted to the
+//! `complex128` arrays used to exchange statevectors and gates with Python.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+#[cfg(test)]
+mod tests;
+
+use faer::{Col, Mat, fx128};
+
+use crate::core::C;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Writes `data`, laid out in row-major order, as a `complex128` array of the
+/// given shape.
+pub fn write_npy(path: &Path, data: &[C], shape: &[usize]) -> io::Result<()> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<c16', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // The data must start on a 64 byte boundary, the header ends with '\n'
+    let total = MAGIC.len() + 4 + header.len() + 1;
+    header += &" ".repeat((64 - total % 64) % 64);
+    header.push('\n');
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend([1, 0]);
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for x in data {
+        bytes.extend(x.re.0.to_le_bytes());
+        bytes.extend(x.im.0.to_le_bytes());
+    }
+    fs::write(path, bytes)
+}
+
+pub fn write_statevector(path: &Path, state: &Col<C>) -> io::Result<()> {
+    let data: Vec<C> = state.iter().copied().collect();
+    write_npy(path, &data, &[data.len()])
+}
+
+pub fn write_matrix(path: &Path, mat: &Mat<C>) -> io::Result<()> {
+    let data: Vec<C> = (0..mat.nrows())
+        .flat_map(|i| (0..mat.ncols()).map(move |j| mat[(i, j)]))
+        .collect();
+    write_npy(path, &data, &[mat.nrows(), mat.ncols()])
+}
+
+fn header_value<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let start = header
+        .find(&format!("'{}':", key))
+        .ok_or_else(|| invalid("missing key in .npy header"))?;
+    Ok(header[start + key.len() + 3..].trim_start())
+}
+
+/// Reads a `complex128` array, returning its shape and its entries in
+/// row-major order.
+pub fn read_npy(path: &Path) -> io::Result<(Vec<usize>, Vec<C>)> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not a .npy file"));
+    }
+    let (len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => return Err(invalid("unsupported .npy version")),
+    };
+    let header = bytes
+        .get(start..start + len)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(|| invalid("invalid .npy header"))?;
+
+    if !header_value(header, "descr")?.starts_with("'<c16'") {
+        return Err(invalid(
+            "only little-endian complex128 arrays are supported",
+        ));
+    }
+    let fortran = header_value(header, "fortran_order")?.starts_with("True");
+    let shape = header_value(header, "shape")?;
+    let shape = &shape[1..shape.find(')').ok_or_else(|| invalid("invalid shape"))?];
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>().map_err(|_| invalid("invalid shape")))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let count: usize = shape.iter().product();
+    let data = &bytes[start + len..];
+    if data.len() != count * 16 {
+        return Err(invalid("data size does not match the shape"));
+    }
+    let f = |i: usize| fx128::from_f64(f64::from_le_bytes(data[i..i + 8].try_into().unwrap()));
+    let mut values: Vec<C> = (0..count)
+        .map(|k| C::new(f(16 * k), f(16 * k + 8)))
+        .collect();
+    if fortran && shape.len() == 2 {
+        let (rows, cols) = (shape[0], shape[1]);
+        values = (0..count)
+            .map(|k| values[(k % cols) * rows + k / cols])
+            .collect();
+    }
+    Ok((shape, values))
+}
+
+pub fn read_matrix(path: &Path) -> io::Result<Mat<C>> {
+    let (shape, values) = read_npy(path)?;
+    match shape[..] {
+        [rows, cols] => Ok(Mat::from_fn(rows, cols, |i, j| values[i * cols + j])),
+        _ => Err(invalid("expected a two-dimensional array")),
+    }
+}
@@ -0,0 +1,24 @@
+use std::fs;
+
+use faer::Col;
+
+use crate::core::{C, cplx, norm};
+use crate::npy::{read_npy, write_statevector};
+
+#[test]
+fn test_statevector_round_trip() {
+    let path = std::env::temp_dir().join("rustomic_npy_round_trip.npy");
+    let state: Col<C> = Col::from_fn(4, |i| cplx(i as f64 * 0.5, -(i as f64)));
+    write_statevector(&path, &state).unwrap();
+
+    // The header is padded so the data starts on a 64 byte boundary
+    let bytes = fs::read(&path).unwrap();
+    assert_eq!((bytes.len() - 4 * 16) % 64, 0);
+
+    let (shape, values) = read_npy(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(shape, vec![4]);
+    for (i, x) in values.iter().enumerate() {
+        assert!(norm(*x - state[i]) < 1e-12);
+    }
+}
@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests;
+
+/// Checks whether two Pauli strings commute qubit by qubit, i.e. on every
+/// position the letters are equal or one of them is `I`.
+pub fn qubit_wise_commute(a: &str, b: &str) -> bool {
+    a.chars()
+        .zip(b.chars())
+        .all(|(x, y)| x == y || x == 'I' || y == 'I')
+}
+
+/// Partitions Pauli strings into sets of qubit-wise commuting observables,
+/// which can be measured together with a single circuit.
+///
+/// Groups are built greedily: each string joins the first group it commutes
+/// with, or starts a new one. Returns the indices into `paulis`.
+pub fn group_commuting(paulis: &[String]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, pauli) in paulis.iter().enumerate() {
+        match groups
+            .iter_mut()
+            .find(|group| group.iter().all(|j| qubit_wise_commute(pauli, &paulis[*j])))
+        {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+    groups
+}
+
+/// Observable `Σ c_k P_k` given as weighted Pauli strings, labelled like
+/// `Circuit::expectation` (the rightmost letter acts on qubit 0).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Hamiltonian {
+    pub terms: Vec<(f64, String)>,
+}
+
+impl Hamiltonian {
+    pub fn new(terms: Vec<(f64, String)>) -> Self {
+        Self { terms }
+    }
+}
@@ -0,0 +1,25 @@
+use crate::observables::{group_commuting, qubit_wise_commute};
+
+fn strings(paulis: &[&str]) -> Vec<String> {
+    paulis.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn test_qubit_wise_commute() {
+    assert!(qubit_wise_commute("ZZ", "ZI"));
+    assert!(qubit_wise_commute("XI", "IY"));
+    assert!(!qubit_wise_commute("ZZ", "XX"));
+    // XX and ZZ commute as operators, but not qubit by qubit
+    assert!(!qubit_wise_commute("XX", "ZZ"));
+}
+
+#[test]
+fn test_group_commuting() {
+    let groups = group_commuting(&strings(&["ZZ", "ZI", "XX"]));
+    assert_eq!(groups, vec![vec![0, 1], vec![2]]);
+
+    let groups = group_commuting(&strings(&["XI", "ZZ", "IX", "IZ", "XX"]));
+    assert_eq!(groups, vec![vec![0, 2, 4], vec![1, 3]]);
+
+    assert!(group_commuting(&[]).is_empty());
+}
@@ -0,0 +1,285 @@
+//! Importer and exporter for a subset of OpenQASM 2 and 3.
+//!
+//! Registers are declared with `qreg q[n];` or `qubit[n] q;` before any gate,
+//! and every operand names a single qubit such as `q[0]`. Gates can be
+//! prefixed with `inv @` to apply their inverse, and with `ctrl @` or
+//! `ctrl(n) @` to control them on their first operands. Classical bits,
+//! measurements and barriers are skipped, custom gate definitions are not
+//! supported.
+//!
+//! `emit` writes circuits back in the same subset, which `parse` reads.
+
+#[cfg(test)]
+mod tests;
+
+use std::f64::consts::PI;
+
+use crate::core::{Circuit, CircuitError, Gate, Scalar, TOLERANCE, mat_approx_eq, phase_angle};
+
+fn error(line: usize, msg: &str) -> CircuitError {
+    CircuitError::Qasm(format!("line {}: {}", line, msg))
+}
+
+/// Evaluates a gate parameter made of numbers and `pi` joined by `*` and
+/// `/`, such as `-3*pi/4`.
+fn parse_param(expr: &str) -> Option<f64> {
+    let expr = expr.trim();
+    let (sign, expr) = match expr.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, expr),
+    };
+    let factor = |s: &str| match s.trim() {
+        "pi" | "π" => Some(PI),
+        s => s.parse::<f64>().ok(),
+    };
+    let mut value = 1.0;
+    let mut divide = false;
+    let mut rest = expr;
+    loop {
+        let end = rest.find(['*', '/']).unwrap_or(rest.len());
+        let x = factor(&rest[..end])?;
+        value = if divide { value / x } else { value * x };
+        if end == rest.len() {
+            return Some(sign * value);
+        }
+        divide = rest[end..].starts_with('/');
+        rest = &rest[end + 1..];
+    }
+}
+
+/// Parses a register declaration, returning its name and size.
+fn parse_register(stmt: &str) -> Option<(String, usize)> {
+    if let Some(rest) = stmt.strip_prefix("qreg ") {
+        let (name, size) = rest.trim().strip_suffix(']')?.split_once('[')?;
+        return Some((name.trim().to_string(), size.trim().parse().ok()?));
+    }
+    let rest = stmt.strip_prefix("qubit")?.trim_start();
+    match rest.strip_prefix('[') {
+        Some(rest) => {
+            let (size, name) = rest.split_once(']')?;
+            Some((name.trim().to_string(), size.trim().parse().ok()?))
+        }
+        None => Some((rest.to_string(), 1)),
+    }
+}
+
+/// Parses an OpenQASM program into a circuit whose registers are the
+/// declared quantum registers, in declaration order.
+pub fn parse(src: &str) -> Result<Circuit, CircuitError> {
+    let mut registers = Vec::new();
+    let mut circuit: Option<Circuit> = None;
+    let mut line = 1;
+    let src: String = src
+        .lines()
+        .map(|l| l.split("//").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    for raw in src.split(';') {
+        let leading = &raw[..raw.len() - raw.trim_start().len()];
+        let stmt_line = line + leading.matches('\n').count();
+        line += raw.matches('\n').count();
+        let stmt = raw.split_whitespace().collect::<Vec<_>>().join(" ");
+        if stmt.is_empty()
+            || stmt.starts_with("OPENQASM")
+            || stmt.starts_with("include")
+            || stmt.starts_with("creg")
+            || stmt.starts_with("bit")
+            || stmt.starts_with("barrier")
+            || stmt.starts_with("measure")
+        {
+            continue;
+        }
+        if stmt.starts_with("qreg ") || stmt.starts_with("qubit") {
+            if circuit.is_some() {
+                return Err(error(stmt_line, "registers must be declared before gates"));
+            }
+            let reg = parse_register(&stmt).ok_or_else(|| error(stmt_line, "invalid register"))?;
+            registers.push(reg);
+            continue;
+        }
+        let circuit = circuit.get_or_insert_with(|| Circuit::with_registers(&registers));
+
+        let mut rest = stmt.as_str();
+        let mut inverse = false;
+        let mut controls = 0;
+        while let Some((modifier, after)) = rest.split_once('@') {
+            match modifier.trim() {
+                "inv" => inverse = !inverse,
+                "ctrl" => controls += 1,
+                m => {
+                    let count = m
+                        .strip_prefix("ctrl(")
+                        .and_then(|m| m.strip_suffix(')'))
+                        .and_then(|n| n.trim().parse::<usize>().ok())
+                        .ok_or_else(|| error(stmt_line, &format!("unknown modifier {}", m)))?;
+                    controls += count;
+                }
+            }
+            rest = after.trim_start();
+        }
+        let (head, operands) = match rest.find(')') {
+            Some(close) => (&rest[..close + 1], &rest[close + 1..]),
+            None => rest.split_at(rest.find(' ').unwrap_or(rest.len())),
+        };
+        let (name, params) = match head.split_once('(') {
+            Some((name, params)) => {
+                let params = params
+                    .trim_end_matches(')')
+                    .split(',')
+                    .map(|p| parse_param(p).ok_or_else(|| error(stmt_line, "invalid parameter")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (name.trim(), params)
+            }
+            None => (head.trim(), Vec::new()),
+        };
+        let qubits = operands
+            .split(',')
+            .map(|op| {
+                let (reg, index) = op
+                    .trim()
+                    .strip_suffix(']')
+                    .and_then(|op| op.split_once('['))
+                    .ok_or_else(|| error(stmt_line, "operands must be single qubits"))?;
+                let index = index
+                    .trim()
+                    .parse()
+                    .map_err(|_| error(stmt_line, "invalid qubit index"))?;
+                circuit.qubit(reg.trim(), index)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if qubits.len() <= controls {
+            return Err(error(stmt_line, "not enough operands for the controls"));
+        }
+        // The first operands are the controls added by `ctrl @`
+        let (control_qubits, targets) = qubits.split_at(controls);
+        let mut gate = Gate::from_name(name, &params, targets)
+            .ok_or_else(|| error(stmt_line, &format!("unknown gate {}", name)))?;
+        if inverse {
+            gate = gate.dagger();
+        }
+        if controls > 0 {
+            gate = gate
+                .controlled(control_qubits.to_vec())
+                .ok_or(CircuitError::InvalidGate)?;
+        }
+        circuit.add_gate(gate)?;
+    }
+    Ok(circuit.unwrap_or_else(|| Circuit::with_registers(&registers)))
+}
+
+/// Recovers the angle of a rotation built by `Gate::from_name`, from the
+/// matrix of the gate without its controls. Other gates have no parameter.
+fn angles(base: &Gate) -> Vec<f64> {
+    let m = base.matrix();
+    let arg = |i: usize| phase_angle(m[(i, i)].real(), m[(i, i)].imag());
+    let last = m.nrows() - 1;
+    let (c, s) = (m[(0, 0)].real(), m[(last, 0)]);
+    match base.name() {
+        "p" => vec![arg(1)],
+        "rz" | "rzz" => vec![arg(1) - arg(0)],
+        "rx" | "rxx" => vec![2.0 * (-s.imag()).atan2(c)],
+        "ry" => vec![2.0 * s.real().atan2(c)],
+        "ryy" => vec![2.0 * s.imag().atan2(c)],
+        _ => Vec::new(),
+    }
+}
+
+/// Checks whether `Gate::from_name` rebuilds `gate` exactly from its name,
+/// parameters and operands.
+fn rebuilds(gate: &Gate, name: &str, params: &[f64], operands: &[usize]) -> bool {
+    Gate::from_name(name, params, operands)
+        .is_some_and(|g| g.qubits() == gate.qubits() && mat_approx_eq(g.matrix(), gate.matrix()))
+}
+
+/// Writes a single gate statement, without its final `;`.
+fn statement(gate: &Gate) -> Result<String, CircuitError> {
+    let controls = gate.controls();
+    let operands: Vec<usize> = controls
+        .iter()
+        .chain(gate.target_qubits())
+        .copied()
+        .collect();
+    let base = gate.uncontrolled();
+    let params = angles(&base);
+    let modifier = match controls.len() {
+        0 => String::new(),
+        1 => "ctrl @ ".to_string(),
+        n => format!("ctrl({}) @ ", n),
+    };
+    let head = if rebuilds(gate, gate.name(), &params, &operands) {
+        gate.name().to_string()
+    } else if rebuilds(&base, base.name(), &params, base.qubits()) {
+        modifier + base.name()
+    } else {
+        // e^{iα} Rz(β) Ry(γ) Rz(δ) = e^{i(α - (β+δ)/2)} U(γ, β, δ)
+        let (alpha, (beta, gamma, delta)) = base
+            .zyz_decompose()
+            .ok_or_else(|| CircuitError::NoDecomposition(gate.name().to_string()))?;
+        let rest = alpha - (beta + delta) / 2.0;
+        let phase = phase_angle(rest.cos(), rest.sin());
+        if !controls.is_empty() && phase.abs() > TOLERANCE {
+            return Err(CircuitError::NoDecomposition(gate.name().to_string()));
+        }
+        return Ok(format!(
+            "{}U({}, {}, {}) {}",
+            modifier,
+            gamma,
+            beta,
+            delta,
+            operand_list(&operands)
+        ));
+    };
+    let params = match params[..] {
+        [] => String::new(),
+        _ => format!(
+            "({})",
+            params
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    Ok(format!("{}{} {}", head, params, operand_list(&operands)))
+}
+
+fn operand_list(qubits: &[usize]) -> String {
+    qubits
+        .iter()
+        .map(|q| format!("q[{}]", q))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes a circuit as an OpenQASM 3 program on a single register `q`.
+///
+/// Gates known to `Gate::from_name` keep their name, with `ctrl @`
+/// modifiers for the controlled versions of other gates, and any other
+/// single-qubit gate is written as `U`. The program only matches the
+/// circuit up to a global phase, since `global_phase` gates and the phase
+/// of uncontrolled `U` gates are dropped. Labels are written as comments
+/// above their gate, one per line of the label.
+///
+/// Fails with `CircuitError::NoDecomposition` for multi-qubit gates with no
+/// name, or controlled gates whose phase `U` can't carry, and with
+/// `CircuitError::ClassicalGate` for measurements and classically
+/// controlled gates.
+pub fn emit(circuit: &Circuit) -> Result<String, CircuitError> {
+    let mut out = format!("OPENQASM 3.0;\nqubit[{}] q;\n", circuit.num_qubits());
+    for (i, gate) in circuit.gates().iter().enumerate() {
+        if gate.is_classical() {
+            return Err(CircuitError::ClassicalGate(i));
+        }
+        if gate.name() == "global_phase" {
+            continue;
+        }
+        if let Some(label) = gate.label() {
+            for line in label.split('\n') {
+                out += &format!("// {}\n", line);
+            }
+        }
+        out += &statement(gate)?;
+        out += ";\n";
+    }
+    Ok(out)
+}
@@ -0,0 +1,125 @@
+use crate::core::{Circuit, CircuitError, Gate, mat_approx_eq};
+
+#[test]
+fn test_inverse_modifier() {
+    let c = Circuit::from_qasm("OPENQASM 3.0;\nqubit[1] q;\ninv @ s q[0];\n").unwrap();
+    let expected = Gate::s(0).dagger();
+    assert_eq!(c.gates().len(), 1);
+    assert_eq!(c.gates()[0].qubits(), expected.qubits());
+    assert_eq!(c.gates()[0].name(), "sdg");
+    assert!(mat_approx_eq(c.gates()[0].matrix(), expected.matrix()));
+
+    // Two modifiers cancel out
+    let c = Circuit::from_qasm("qreg q[1]; inv @ inv @ t q[0];").unwrap();
+    assert!(mat_approx_eq(c.gates()[0].matrix(), Gate::t(0).matrix()));
+
+    assert_eq!(
+        Circuit::from_qasm("qreg q[1];\n\ninv @ foo q[0];").unwrap_err(),
+        CircuitError::Qasm("line 3: unknown gate foo".to_string())
+    );
+}
+
+#[test]
+fn test_bell_program() {
+    let src = "
+        OPENQASM 2.0;
+        include \"qelib1.inc\";
+        qreg a[1];
+        qreg b[1];
+        creg c[2];
+        measure a[0] -> c[0];
+        h a[0]; // put a in superposition
+        cx a[0], b[0];
+        p(-pi/2) b[0];
+    ";
+    let c = Circuit::from_qasm(src).unwrap();
+    assert_eq!(c.num_qubits(), 2);
+    assert_eq!(c.gate_order(), ["h", "cx", "p"]);
+    let mut expected = Circuit::new(2);
+    expected.h(0).unwrap();
+    expected.cx(0, 1).unwrap();
+    expected.p(-std::f64::consts::FRAC_PI_2, 1).unwrap();
+    assert!(c.equiv(&expected));
+
+    assert_eq!(
+        Circuit::from_qasm("qreg q[2]; cx q[0], q[2];").unwrap_err(),
+        CircuitError::QubitOutOfRange(2)
+    );
+    assert!(Circuit::from_qasm("qreg q[2]; h q;").is_err());
+}
+
+#[test]
+fn test_control_modifier() {
+    let c = Circuit::from_qasm("qreg q[2]; ctrl @ x q[0], q[1];").unwrap();
+    assert_eq!(c.gates()[0].name(), "cx");
+    assert!(mat_approx_eq(
+        &c.gates()[0].turn_big(2),
+        &Gate::cx(0, 1).unwrap().turn_big(2)
+    ));
+
+    // Stacked modifiers accumulate controls
+    let c = Circuit::from_qasm("qreg q[4]; ctrl @ ctrl(2) @ x q[3], q[0], q[2], q[1];").unwrap();
+    let expected = Gate::cnx(vec![3, 0, 2], 1).unwrap();
+    assert!(mat_approx_eq(
+        &c.gates()[0].turn_big(4),
+        &expected.turn_big(4)
+    ));
+
+    let c = Circuit::from_qasm("qreg q[2]; inv @ ctrl @ s q[1], q[0];").unwrap();
+    let expected = Gate::s(0).dagger().controlled(vec![1]).unwrap();
+    assert!(mat_approx_eq(
+        &c.gates()[0].turn_big(2),
+        &expected.turn_big(2)
+    ));
+
+    assert!(Circuit::from_qasm("qreg q[2]; ctrl @ x q[0];").is_err());
+    assert!(Circuit::from_qasm("qreg q[2]; ctrl @ x q[0], q[0];").is_err());
+    assert!(Circuit::from_qasm("qreg q[2]; pow(2) @ x q[0];").is_err());
+}
+
+#[test]
+fn test_emit_round_trip() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.cx(0, 1).unwrap();
+    c.rx(0.3, 2).unwrap();
+    c.ry(-1.2, 0).unwrap();
+    c.rzz(2.5, 1, 2).unwrap();
+    c.cp(0.7, 2, 0).unwrap();
+    c.swap(0, 2).unwrap();
+    c.add_gate(Gate::s(1).dagger()).unwrap();
+    c.add_gate(Gate::mcrz(-0.4, vec![0, 1], 2).unwrap())
+        .unwrap();
+    c.add_gate(Gate::y(1).controlled(vec![2]).unwrap()).unwrap();
+    c.add_gate(Gate::from_euler("ZYZ", (0.1, 0.2, 0.3), 1).unwrap())
+        .unwrap();
+    c.global_phase(0.5).unwrap();
+    let src = c.to_qasm().unwrap();
+    assert!(src.starts_with("OPENQASM 3.0;\nqubit[3] q;\nh q[0];\ncx q[0], q[1];\n"));
+    assert!(src.contains("ctrl(2) @ rz(-0.4"));
+    assert!(src.contains("ctrl @ y q[2], q[1];"));
+    assert!(Circuit::from_qasm(&src).unwrap().equiv(&c));
+
+    // Unnamed two-qubit unitaries have no OpenQASM equivalent
+    let mut c = Circuit::new(2);
+    let rzz = Gate::rzz(0.5, 0, 1).unwrap();
+    c.unitary_gate(rzz.matrix().clone(), vec![0, 1]).unwrap();
+    assert_eq!(
+        c.to_qasm().unwrap_err(),
+        CircuitError::NoDecomposition("unitary".to_string())
+    );
+}
+
+#[test]
+fn test_emit_multiline_label() {
+    let mut c = Circuit::new(1);
+    c.add_gate(Gate::x(0).with_label("x\nh q[0];".to_string()))
+        .unwrap();
+    let src = c.to_qasm().unwrap();
+    assert_eq!(
+        src,
+        "OPENQASM 3.0;\nqubit[1] q;\n// x\n// h q[0];\nx q[0];\n"
+    );
+    let parsed = Circuit::from_qasm(&src).unwrap();
+    assert_eq!(parsed.gate_order(), vec!["x"]);
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod tests;
+
+use crate::core::{Circuit, CircuitError, Gate, mat_approx_eq};
+
+/// Stabilizer tableau (Aaronson-Gottesman) simulating Clifford circuits in
+/// polynomial time.
+///
+/// Rows `0..n` hold the destabilizers, rows `n..2n` the stabilizers and row
+/// `2n` is scratch space used by deterministic measurements.
+#[derive(Clone)]
+pub struct StabilizerSim {
+    n: usize,
+    x: Vec<Vec<bool>>,
+    z: Vec<Vec<bool>>,
+    r: Vec<bool>,
+}
+
+enum Clifford {
+    H(usize),
+    S(usize),
+    X(usize),
+    Z(usize),
+    Cx(usize, usize),
+}
+
+fn classify(gate: &Gate) -> Option<Clifford> {
+    let m = gate.matrix();
+    match gate.qubits() {
+        [q] => {
+            let q = *q;
+            if mat_approx_eq(m, Gate::h(0).matrix()) {
+                Some(Clifford::H(q))
+            } else if mat_approx_eq(m, Gate::s(0).matrix()) {
+                Some(Clifford::S(q))
+            } else if mat_approx_eq(m, Gate::x(0).matrix()) {
+                Some(Clifford::X(q))
+            } else if mat_approx_eq(m, Gate::z(0).matrix()) {
+                Some(Clifford::Z(q))
+            } else {
+                None
+            }
+        }
+        [a, b] => {
+            // Bit i of the gate's matrix index refers to its i-th qubit
+            if mat_approx_eq(m, &Gate::cx(1, 0)?.turn_big(2)) {
+                Some(Clifford::Cx(*b, *a))
+            } else if mat_approx_eq(m, &Gate::cx(0, 1)?.turn_big(2)) {
+                Some(Clifford::Cx(*a, *b))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl StabilizerSim {
+    /// Creates the tableau of the `|0...0⟩` state on `n` qubits.
+    pub fn new(n: usize) -> Self {
+        let mut x = vec![vec![false; n]; 2 * n + 1];
+        let mut z = vec![vec![false; n]; 2 * n + 1];
+        for i in 0..n {
+            x[i][i] = true;
+            z[n + i][i] = true;
+        }
+        Self {
+            n,
+            x,
+            z,
+            r: vec![false; 2 * n + 1],
+        }
+    }
+
+    /// Builds the tableau of the state prepared by `circuit` from `|0...0⟩`.
+    ///
+    /// Fails with `CircuitError::NonClifford` if any gate isn't H, S, X, Z or CX,
+    /// and with `CircuitError::ClassicalGate` for classical gates.
+    pub fn from_circuit(circuit: &Circuit) -> Result<Self, CircuitError> {
+        let mut sim = Self::new(circuit.num_qubits());
+        for (i, gate) in circuit.gates().iter().enumerate() {
+            if gate.is_classical() {
+                return Err(CircuitError::ClassicalGate(i));
+            }
+            match classify(gate).ok_or(CircuitError::NonClifford)? {
+                Clifford::H(q) => sim.h(q),
+                Clifford::S(q) => sim.s(q),
+                Clifford::X(q) => sim.x(q),
+                Clifford::Z(q) => sim.z(q),
+                Clifford::Cx(c, t) => sim.cx(c, t),
+            }
+        }
+        Ok(sim)
+    }
+
+    pub fn h(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][a];
+            std::mem::swap(&mut self.x[i][a], &mut self.z[i][a]);
+        }
+    }
+
+    pub fn s(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][a];
+            self.z[i][a] ^= self.x[i][a];
+        }
+    }
+
+    pub fn x(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.z[i][a];
+        }
+    }
+
+    pub fn z(&mut self, a: usize) {
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a];
+        }
+    }
+
+    pub fn cx(&mut self, control: usize, target: usize) {
+        let (a, b) = (control, target);
+        for i in 0..2 * self.n {
+            self.r[i] ^= self.x[i][a] && self.z[i][b] && (self.x[i][b] == self.z[i][a]);
+            self.x[i][b] ^= self.x[i][a];
+            self.z[i][a] ^= self.z[i][b];
+        }
+    }
+
+    /// Multiplies row `h` by row `i`, keeping track of the phase.
+    fn rowsum(&mut self, h: usize, i: usize) {
+        let mut phase = 2 * self.r[h] as i32 + 2 * self.r[i] as i32;
+        for j in 0..self.n {
+            let (x1, z1) = (self.x[i][j] as i32, self.z[i][j] as i32);
+            let (x2, z2) = (self.x[h][j] as i32, self.z[h][j] as i32);
+            phase += match (x1, z1) {
+                (0, 0) => 0,
+                (1, 1) => z2 - x2,
+                (1, 0) => z2 * (2 * x2 - 1),
+                _ => x2 * (1 - 2 * z2),
+            };
+            self.x[h][j] ^= self.x[i][j];
+            self.z[h][j] ^= self.z[i][j];
+        }
+        self.r[h] = phase.rem_euclid(4) == 2;
+    }
+
+    /// Returns the outcome of measuring qubit `a` if it is deterministic.
+    pub fn deterministic_outcome(&mut self, a: usize) -> Option<bool> {
+        let n = self.n;
+        if (n..2 * n).any(|p| self.x[p][a]) {
+            return None;
+        }
+        self.x[2 * n].fill(false);
+        self.z[2 * n].fill(false);
+        self.r[2 * n] = false;
+        for i in 0..n {
+            if self.x[i][a] {
+                self.rowsum(2 * n, i + n);
+            }
+        }
+        Some(self.r[2 * n])
+    }
+
+    /// Measures qubit `a`, forcing `outcome` when the result is random.
+    ///
+    /// Returns the measured value and whether it was random.
+    pub fn measure(&mut self, a: usize, outcome: bool) -> (bool, bool) {
+        if let Some(value) = self.deterministic_outcome(a) {
+            return (value, false);
+        }
+        let n = self.n;
+        let p = (n..2 * n).find(|p| self.x[*p][a]).unwrap();
+        for i in 0..2 * n {
+            if i != p && self.x[i][a] {
+                self.rowsum(i, p);
+            }
+        }
+        self.x[p - n] = self.x[p].clone();
+        self.z[p - n] = self.z[p].clone();
+        self.r[p - n] = self.r[p];
+        self.x[p].fill(false);
+        self.z[p].fill(false);
+        self.z[p][a] = true;
+        self.r[p] = outcome;
+        (outcome, true)
+    }
+
+    /// Returns the probability of every possible outcome of measuring all
+    /// qubits, using the same bitstring labels as `Circuit::run`.
+    ///
+    /// Only outcomes with non-zero probability are listed, so the cost is
+    /// proportional to the size of the support rather than `2^n`.
+    pub fn probabilities(&self) -> HashMap<String, f64> {
+        let mut res = HashMap::new();
+        self.branch(0, 0, 1.0, &mut res);
+        res
+    }
+
+    fn branch(&self, qubit: usize, index: usize, prob: f64, res: &mut HashMap<String, f64>) {
+        if qubit == self.n {
+            res.insert(format!("{:0width$b}", index, width = self.n), prob);
+            return;
+        }
+        for outcome in [false, true] {
+            let mut sim = self.clone();
+            let (value, random) = sim.measure(qubit, outcome);
+            let index = index | ((value as usize) << qubit);
+            if random {
+                sim.branch(qubit + 1, index, prob / 2.0, res);
+            } else {
+                sim.branch(qubit + 1, index, prob, res);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::core::{Circuit, CircuitError, norm};
+
+#[test]
+fn test_ghz_20_qubits() {
+    let n = 20;
+    let mut c = Circuit::new(n);
+    c.h(0).unwrap();
+    for i in 1..n {
+        c.cx(0, i).unwrap();
+    }
+    let probs = c.run_stabilizer().unwrap();
+    assert_eq!(probs.len(), 2);
+    assert!((probs[&"0".repeat(n)] - 0.5).abs() < 1e-12);
+    assert!((probs[&"1".repeat(n)] - 0.5).abs() < 1e-12);
+}
+
+#[test]
+fn test_non_clifford_rejected() {
+    let mut c = Circuit::new(1);
+    c.h(0).unwrap();
+    c.t(0).unwrap();
+    assert_eq!(c.run_stabilizer(), Err(CircuitError::NonClifford));
+}
+
+#[test]
+fn test_matches_statevector() {
+    let mut c = Circuit::new(3);
+    c.h(0).unwrap();
+    c.s(0).unwrap();
+    c.cx(0, 2).unwrap();
+    c.h(2).unwrap();
+    c.x(1).unwrap();
+    c.z(2).unwrap();
+    c.cx(2, 1).unwrap();
+    c.s(1).unwrap();
+    c.h(1).unwrap();
+
+    let probs = c.run_stabilizer().unwrap();
+    let amps = c.run().unwrap();
+    for (bits, amp) in amps {
+        let expected = norm(amp) * norm(amp);
+        let got = probs.get(&bits).copied().unwrap_or(0.0);
+        assert!(
+            (expected - got).abs() < 1e-5,
+            "{}: {} vs {}",
+            bits,
+            expected,
+            got
+        );
+    }
+}
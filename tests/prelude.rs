@@ -0,0 +1,19 @@
+use rustomic::prelude::*;
+
+#[test]
+fn custom_gate_through_prelude() {
+    // √Z, i.e. the S gate, written by hand
+    let m: Mat<C> = mat![[ONE, Z], [Z, IM]];
+    let root_z = Gate::new(m, vec![1]).unwrap();
+
+    let mut c = Circuit::new(2);
+    c.x(1).unwrap();
+    c.add_gate(root_z.clone()).unwrap();
+    c.add_gate(root_z).unwrap();
+    let state: Col<C> = c.statevector().unwrap();
+    let minus_one = cplx(-1.0, 0.0);
+    assert!(norm(state[2] - minus_one) < 1e-10);
+
+    let bad = Mat::from_fn(2, 2, |_, _| ONE);
+    assert!(Gate::new(bad, vec![0]).is_none());
+}